@@ -0,0 +1,344 @@
+use std::fmt;
+use std::future::Future;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::events::{report_bridge_error, Event};
+use crate::message::{encode_error, EnvelopeFormat, Message, OutgoingMessage};
+use crate::node::PublishRequest;
+use crate::tasks::TaskGroup;
+
+/// Connects local applications to the node, for example via UDP or Unix sockets.
+///
+/// Bridges receive payloads from local applications which should be published and deliver
+/// messages received from the network to them. Publishing, ingesting and forwarding operations is
+/// handled by the node, a bridge only needs to take care of its transport. Servers with multiple
+/// connections, like the TCP and WebSocket server, run one bridge per connection.
+#[async_trait]
+pub trait Bridge: Send + 'static {
+    /// Name of the bridge, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Waits for the next message from the local application which should be published.
+    ///
+    /// Returns nothing when the local application closed the connection, the bridge stops then.
+    /// This method needs to be cancel safe. Errors are fatal and stop the bridge.
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>>;
+
+    /// Delivers a message received from the network to the local application.
+    ///
+    /// Errors are reported and the bridge keeps running.
+    async fn deliver(&mut self, message: &Message) -> Result<()>;
+
+    /// Called when the bridge stops, after all pending messages were delivered.
+    ///
+    /// Use this to flush delivered messages which are written in the background.
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the bridge until it fails, the local application closes the connection or the node shuts
+/// down.
+///
+/// On shutdown all messages which were already received from the network are delivered before
+/// the bridge stops.
+pub async fn run_bridge<B: Bridge>(
+    mut bridge: B,
+    publish_tx: mpsc::Sender<PublishRequest>,
+    mut messages_rx: broadcast::Receiver<Message>,
+    events_tx: broadcast::Sender<Event>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let name = bridge.name();
+    let mut closed = false;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            message = bridge.recv() => {
+                let message = match message {
+                    Ok(Some(message)) => message,
+                    Ok(None) => {
+                        // Connections also close on shutdown, deliver pending messages then.
+                        closed = !shutdown.is_cancelled();
+                        break;
+                    }
+                    Err(err) => {
+                        error!(bridge = name, "stop bridge: {err}");
+                        report_bridge_error(&events_tx, name, &err);
+                        return Err(err);
+                    }
+                };
+
+                if publish_tx.send(message.into()).await.is_err() {
+                    break;
+                }
+            }
+            message = messages_rx.recv() => {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(bridge = name, skipped, "bridge lagged behind, skip messages");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if let Err(err) = bridge.deliver(&message).await {
+                    warn!(bridge = name, "could not deliver message: {err}");
+                    report_bridge_error(&events_tx, name, &err);
+                }
+            }
+        }
+    }
+
+    // Nobody is listening anymore when the local application closed the connection.
+    if !closed {
+        loop {
+            let message = match messages_rx.try_recv() {
+                Ok(message) => message,
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            };
+
+            if let Err(err) = bridge.deliver(&message).await {
+                warn!(bridge = name, "could not deliver message: {err}");
+            }
+        }
+    }
+
+    if let Err(err) = bridge.close().await {
+        warn!(bridge = name, "could not close bridge: {err}");
+    }
+
+    debug!(bridge = name, "bridge stopped");
+
+    Ok(())
+}
+
+/// Decodes a message sent by a local application, either an envelope in the given format or the
+/// plain payload.
+pub fn decode_input(input: Option<EnvelopeFormat>, bytes: &[u8]) -> Result<OutgoingMessage> {
+    match input {
+        // Envelopes are validated while decoding them.
+        Some(format) => OutgoingMessage::from_envelope(bytes, format),
+        None => {
+            let message = OutgoingMessage::new(bytes.to_vec());
+            message.validate()?;
+            Ok(message)
+        }
+    }
+}
+
+/// Checks the size of a datagram sent to the UDP server or Unix socket.
+///
+/// Datagrams are received into a buffer which is one byte larger than the max. message size, so
+/// we can tell when they exceeded it.
+pub fn check_datagram_size(len: usize, max_message_size: usize) -> Result<()> {
+    if len > max_message_size {
+        bail!("datagram exceeds max. message size of {max_message_size} bytes");
+    }
+    Ok(())
+}
+
+/// Encodes an error for a local application which is using envelopes, applications sending plain
+/// payloads don't expect any replies.
+pub fn error_reply(input: Option<EnvelopeFormat>, err: &impl fmt::Display) -> Option<Vec<u8>> {
+    match encode_error(&err.to_string(), input?) {
+        Ok(bytes) => Some(bytes),
+        Err(err) => {
+            error!("could not encode error envelope: {err}");
+            None
+        }
+    }
+}
+
+/// Frame written to a connection of a local application.
+pub trait Frame: Send + Sync + Sized + 'static {
+    /// Encodes a message received from the network.
+    fn encode(message: &Message, envelope: Option<EnvelopeFormat>) -> Result<Self>;
+
+    /// Wraps an encoded error envelope.
+    fn error(bytes: Vec<u8>, format: EnvelopeFormat) -> Result<Self>;
+}
+
+/// Connection of a local application, for example via TCP or WebSocket.
+///
+/// Frames are read and written in separate tasks of the server's group, as reading them is often
+/// not cancel safe and malformed messages are answered right away.
+#[derive(Debug)]
+pub struct ConnectionBridge<F> {
+    name: &'static str,
+    frames_rx: mpsc::Receiver<OutgoingMessage>,
+    writer_tx: Option<mpsc::Sender<F>>,
+    writer_done: Option<oneshot::Receiver<()>>,
+    envelope: Option<EnvelopeFormat>,
+}
+
+impl<F: Frame> ConnectionBridge<F> {
+    /// Spawns the reader and the writer of the connection in the given group.
+    ///
+    /// The reader stops when the group shuts down, the writer keeps running until all pending
+    /// frames were written.
+    pub fn spawn<R, W>(
+        name: &'static str,
+        tasks: &TaskGroup,
+        input: Option<EnvelopeFormat>,
+        envelope: Option<EnvelopeFormat>,
+        events_tx: broadcast::Sender<Event>,
+        reader: impl FnOnce(ConnectionInput<F>) -> R,
+        writer: impl FnOnce(mpsc::Receiver<F>) -> W,
+    ) -> Self
+    where
+        R: Future<Output = Result<()>> + Send + 'static,
+        W: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (frames_tx, frames_rx) = mpsc::channel(16);
+        let (writer_tx, writer_rx) = mpsc::channel(16);
+        let (writer_done_tx, writer_done) = oneshot::channel();
+
+        let reader = reader(ConnectionInput {
+            name,
+            input,
+            frames_tx,
+            writer_tx: writer_tx.clone(),
+            events_tx: events_tx.clone(),
+        });
+        tasks.spawn(name, async move {
+            if let Err(err) = reader.await {
+                warn!(bridge = name, "connection error: {err:#}");
+                report_bridge_error(&events_tx, name, &format!("{err:#}"));
+            }
+            debug!(bridge = name, "connection closed");
+            Ok(())
+        });
+
+        let writer = writer(writer_rx);
+        tasks.spawn_graceful(name, |_| async move {
+            if let Err(err) = writer.await {
+                debug!(bridge = name, "connection closed: {err}");
+            }
+            let _ = writer_done_tx.send(());
+            Ok(())
+        });
+
+        Self {
+            name,
+            frames_rx,
+            writer_tx: Some(writer_tx),
+            writer_done: Some(writer_done),
+            envelope,
+        }
+    }
+}
+
+#[async_trait]
+impl<F: Frame> Bridge for ConnectionBridge<F> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>> {
+        Ok(self.frames_rx.recv().await)
+    }
+
+    async fn deliver(&mut self, message: &Message) -> Result<()> {
+        let frame = F::encode(message, self.envelope)?;
+        if let Some(writer_tx) = &self.writer_tx {
+            writer_tx.send(frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // The writer stops after all frames were written, as soon as all senders are dropped.
+        self.writer_tx.take();
+        if let Some(writer_done) = self.writer_done.take() {
+            // Fails when the writer was aborted, nothing can be written anymore then.
+            let _ = writer_done.await;
+        }
+        Ok(())
+    }
+}
+
+/// Hands everything the reader of a connection receives over to the bridge.
+#[derive(Debug)]
+pub struct ConnectionInput<F> {
+    name: &'static str,
+    input: Option<EnvelopeFormat>,
+    frames_tx: mpsc::Sender<OutgoingMessage>,
+    writer_tx: mpsc::Sender<F>,
+    events_tx: broadcast::Sender<Event>,
+}
+
+impl<F: Frame> ConnectionInput<F> {
+    /// Format of the envelopes the local application sends, if it is using them.
+    pub fn input(&self) -> Option<EnvelopeFormat> {
+        self.input
+    }
+
+    /// Decodes a message read from the connection and hands it over for publishing.
+    ///
+    /// Malformed messages are answered with an error when the local application is using
+    /// envelopes, otherwise they are reported. Fails when the connection was closed.
+    pub async fn push(&self, bytes: &[u8], input: Option<EnvelopeFormat>) -> Result<()> {
+        let err = match decode_input(input, bytes) {
+            Ok(message) => {
+                self.frames_tx.send(message).await?;
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+
+        warn!(bridge = self.name, "invalid message: {err}");
+        match (input, error_reply(input, &err)) {
+            (Some(format), Some(reply)) => self.writer_tx.send(F::error(reply, format)?).await?,
+            _ => report_bridge_error(&self.events_tx, self.name, &err),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::EnvelopeFormat;
+    use crate::node::MAX_PUBLISH_SIZE;
+
+    use super::{check_datagram_size, decode_input, error_reply};
+
+    #[test]
+    fn decode_plain_payloads_and_envelopes() {
+        let message = decode_input(None, b"hello").unwrap();
+        assert_eq!(message.payload, b"hello");
+        assert_eq!(message.topic, None);
+
+        let envelope = br#"{"payload":"68656c6c6f","ttl":10}"#;
+        let message = decode_input(Some(EnvelopeFormat::Json), envelope).unwrap();
+        assert_eq!(message.payload, b"hello");
+        assert_eq!(message.ttl, Some(10));
+    }
+
+    #[test]
+    fn validate_plain_payloads() {
+        let payload = vec![0; MAX_PUBLISH_SIZE + 1];
+        assert!(decode_input(None, &payload).is_err());
+    }
+
+    #[test]
+    fn reply_only_with_envelopes() {
+        let err = check_datagram_size(11, 10).unwrap_err();
+        assert!(error_reply(None, &err).is_none());
+
+        let reply = error_reply(Some(EnvelopeFormat::Json), &err).unwrap();
+        assert_eq!(
+            reply,
+            br#"{"error":"datagram exceeds max. message size of 10 bytes"}"#
+        );
+    }
+}
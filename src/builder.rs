@@ -0,0 +1,731 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use p2panda_core::{PrivateKey, PublicKey};
+
+use crate::message::EnvelopeFormat;
+use crate::mqtt::MqttConfig;
+use crate::node::{Config, Node, MAX_TOPICS};
+use crate::operation::MAX_POW_DIFFICULTY;
+use crate::osc::OscConfig;
+use crate::pipe::Framing;
+use crate::rate_limit::PublishLimit;
+use crate::topic::Topic;
+use crate::udp::MAX_DATAGRAM_SIZE;
+
+/// Configures and launches a node.
+///
+/// Unlike the default [`Config`], the builder starts with all subsystems disabled. Callers opt
+/// into local discovery, sync and every bridge they need. The configuration is validated before
+/// anything is launched.
+#[derive(Clone, Debug)]
+pub struct NodeBuilder {
+    private_key: Option<PrivateKey>,
+    config: Config,
+}
+
+impl NodeBuilder {
+    pub fn new() -> Self {
+        Self::from_config(Config {
+            local_discovery: false,
+            no_sync: true,
+            udp_server_addr: None,
+            udp_client_addrs: Vec::new(),
+            ..Default::default()
+        })
+    }
+
+    /// Starts with an existing configuration, all subsystems enabled in it are launched.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            private_key: None,
+            config,
+        }
+    }
+
+    /// Signs all published operations with this key, a new one is generated when none is given.
+    pub fn private_key(mut self, private_key: PrivateKey) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    /// Default topic for messages from local bridges.
+    pub fn topic(mut self, topic: Topic) -> Self {
+        self.config.topic = topic;
+        self
+    }
+
+    /// Finds other peers in the local network via mDNS.
+    pub fn local_discovery(mut self) -> Self {
+        self.config.local_discovery = true;
+        self
+    }
+
+    /// Connects to this peer to find others over the internet.
+    pub fn bootstrap(mut self, public_key: PublicKey) -> Self {
+        self.config.bootstrap = Some(public_key);
+        self
+    }
+
+    /// Syncs past messages with other peers.
+    pub fn sync(mut self) -> Self {
+        self.config.no_sync = false;
+        self
+    }
+
+    /// Launches an UDP server on the given address, messages received from the network are sent
+    /// to the client addresses.
+    pub fn udp(mut self, server_addr: SocketAddr, client_addrs: Vec<SocketAddr>) -> Self {
+        self.config.udp_server_addr = Some(server_addr);
+        self.config.udp_client_addrs = client_addrs;
+        self
+    }
+
+    /// Sends messages back to the addresses which sent data to the UDP server, instead of the UDP
+    /// client addresses.
+    pub fn udp_reply_to_senders(mut self, expiry: Duration) -> Self {
+        self.config.udp_reply_to_senders = true;
+        self.config.udp_sender_expiry = expiry;
+        self
+    }
+
+    /// Allows applications to subscribe to topics via control messages sent to the UDP server.
+    ///
+    /// Subscriptions expire unless the application keeps sending datagrams to the UDP server.
+    pub fn udp_subscriptions(mut self, expiry: Duration) -> Self {
+        self.config.udp_subscriptions = true;
+        self.config.udp_subscription_expiry = expiry;
+        self
+    }
+
+    /// Sends messages to a multicast group or broadcast address instead of the UDP clients.
+    pub fn udp_multicast(mut self, addr: SocketAddr, ttl: u8, interface: Option<Ipv4Addr>) -> Self {
+        self.config.udp_multicast_addr = Some(addr);
+        self.config.udp_multicast_ttl = ttl;
+        self.config.udp_multicast_interface = interface;
+        self
+    }
+
+    /// Validates, filters and routes Open Sound Control packets sent via UDP.
+    pub fn osc(mut self, osc: OscConfig) -> Self {
+        self.config.osc = Some(osc);
+        self
+    }
+
+    /// Wraps messages delivered to local applications in an envelope with all metadata.
+    pub fn envelope(mut self, format: EnvelopeFormat) -> Self {
+        self.config.udp_envelope = Some(format);
+        self
+    }
+
+    /// Expects local applications to send envelopes with publishing options.
+    pub fn input_envelope(mut self, format: EnvelopeFormat) -> Self {
+        self.config.udp_input = Some(format);
+        self
+    }
+
+    /// Maximum size of datagrams we accept from local applications.
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = max_message_size;
+        self
+    }
+
+    /// Launches a TCP server with length-prefixed messages on the given address.
+    pub fn tcp(mut self, addr: SocketAddr) -> Self {
+        self.config.tcp_server_addr = Some(addr);
+        self
+    }
+
+    /// Binds an Unix socket at the given path, messages received from the network are sent to the
+    /// client paths.
+    pub fn unix(mut self, server_path: PathBuf, client_paths: Vec<PathBuf>) -> Self {
+        self.config.unix_server_path = Some(server_path);
+        self.config.unix_client_paths = client_paths;
+        self
+    }
+
+    /// Launches a WebSocket server on the given address.
+    pub fn websocket(mut self, addr: SocketAddr) -> Self {
+        self.config.websocket_addr = Some(addr);
+        self
+    }
+
+    /// Allows browsers to connect to the WebSocket server and publish via the HTTP API from pages
+    /// of these origins, for example "https://example.org".
+    ///
+    /// By default only pages served from the same host are allowed.
+    pub fn allowed_origins(mut self, origins: Vec<String>) -> Self {
+        self.config.allowed_origins = origins;
+        self
+    }
+
+    /// Launches the HTTP API on the given address.
+    pub fn http(mut self, addr: SocketAddr) -> Self {
+        self.config.http_addr = Some(addr);
+        self
+    }
+
+    /// Connects to a MQTT broker.
+    pub fn mqtt(mut self, mqtt: MqttConfig) -> Self {
+        self.config.mqtt = Some(mqtt);
+        self
+    }
+
+    /// Publishes data read from stdin and writes received data to stdout.
+    pub fn pipe(mut self, framing: Framing) -> Self {
+        self.config.pipe = Some(framing);
+        self
+    }
+
+    /// Limits the number of operations and bytes per second we accept from every author.
+    pub fn max_author_rate(mut self, operations: Option<u32>, bytes: Option<u32>) -> Self {
+        self.config.max_author_operations = operations;
+        self.config.max_author_bytes = bytes;
+        self
+    }
+
+    /// Limits the rate of messages from local bridges we publish to the topic.
+    pub fn publish_limit(mut self, topic: Topic, limit: PublishLimit) -> Self {
+        self.config.publish_limits.insert(topic, limit);
+        self
+    }
+
+    /// Rejects operations with timestamps too far in the future.
+    pub fn max_clock_drift(mut self, max_drift: Duration) -> Self {
+        self.config.max_clock_drift = Some(max_drift);
+        self
+    }
+
+    /// Rejects operations older than this when they arrive via gossip instead of sync.
+    pub fn max_gossip_age(mut self, max_age: Duration) -> Self {
+        self.config.max_gossip_age = Some(max_age);
+        self
+    }
+
+    /// Rejects all further operations of authors who forked their log.
+    pub fn block_forks(mut self) -> Self {
+        self.config.block_forks = true;
+        self
+    }
+
+    /// Requires a proof-of-work with the given number of leading zero bits for every operation in
+    /// the topic.
+    pub fn pow_difficulty(mut self, topic: Topic, difficulty: u8) -> Self {
+        self.config.pow_difficulties.insert(topic, difficulty);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Checks the configuration for conflicting options and setups which would send messages in a
+    /// loop.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let config = &self.config;
+
+        if config.udp_server_addr.is_none() {
+            let udp_options = [
+                (config.udp_reply_to_senders, "reply to senders"),
+                (config.udp_subscriptions, "udp subscriptions"),
+                (config.udp_multicast_addr.is_some(), "udp multicast"),
+                (config.osc.is_some(), "osc"),
+            ];
+            for (enabled, option) in udp_options {
+                if enabled {
+                    return Err(ConfigError::Requires {
+                        option,
+                        requires: "udp server",
+                    });
+                }
+            }
+        }
+
+        if config.udp_reply_to_senders && config.udp_multicast_addr.is_some() {
+            return Err(ConfigError::Conflicts {
+                option: "reply to senders",
+                conflicts_with: "udp multicast",
+            });
+        }
+
+        if config.osc.is_some() && (config.udp_envelope.is_some() || config.udp_input.is_some()) {
+            return Err(ConfigError::Conflicts {
+                option: "osc",
+                conflicts_with: "envelope",
+            });
+        }
+
+        if config.no_sync && config.max_gossip_age.is_some() {
+            // Old messages are only accepted via sync, without it they are lost.
+            return Err(ConfigError::Conflicts {
+                option: "no sync",
+                conflicts_with: "max gossip age",
+            });
+        }
+
+        if !config.unix_client_paths.is_empty() && config.unix_server_path.is_none() {
+            return Err(ConfigError::Requires {
+                option: "unix client",
+                requires: "unix server",
+            });
+        }
+
+        if !config.allowed_origins.is_empty()
+            && config.websocket_addr.is_none()
+            && config.http_addr.is_none()
+        {
+            return Err(ConfigError::Requires {
+                option: "allowed origins",
+                requires: "websocket or http server",
+            });
+        }
+
+        if let Some(mqtt) = &config.mqtt {
+            if mqtt.mappings.is_empty() {
+                return Err(ConfigError::Requires {
+                    option: "mqtt broker",
+                    requires: "mqtt mapping",
+                });
+            }
+
+            // The topics of all mappings are joined when the node is launched.
+            let mut topics: HashSet<&Topic> =
+                mqtt.mappings.iter().map(|mapping| &mapping.topic).collect();
+            topics.insert(&config.topic);
+            if topics.len() > MAX_TOPICS {
+                return Err(ConfigError::TooManyTopics(topics.len()));
+            }
+        }
+
+        let expiries = [
+            (config.udp_reply_to_senders, config.udp_sender_expiry),
+            (config.udp_subscriptions, config.udp_subscription_expiry),
+        ];
+        for (enabled, expiry) in expiries {
+            if enabled && expiry.is_zero() {
+                return Err(ConfigError::InvalidSenderExpiry);
+            }
+        }
+
+        if !(1..=MAX_DATAGRAM_SIZE).contains(&config.max_message_size) {
+            return Err(ConfigError::InvalidMaxMessageSize(config.max_message_size));
+        }
+
+        if config.max_author_operations == Some(0) || config.max_author_bytes == Some(0) {
+            return Err(ConfigError::InvalidAuthorRateLimit);
+        }
+
+        for limit in config.publish_limits.values() {
+            if limit.rate == 0 || limit.burst == 0 {
+                return Err(ConfigError::InvalidPublishLimit);
+            }
+        }
+
+        for difficulty in config.pow_difficulties.values() {
+            if !(1..=MAX_POW_DIFFICULTY).contains(difficulty) {
+                return Err(ConfigError::InvalidPowDifficulty(*difficulty));
+            }
+        }
+
+        // Data sent to our own UDP server would be published again and come back to us.
+        if let Some(server_addr) = config.udp_server_addr {
+            let mut client_addrs: Vec<SocketAddr> = config.udp_client_addrs.clone();
+            client_addrs.extend(config.udp_multicast_addr);
+            if let Some(osc) = &config.osc {
+                client_addrs.extend(osc.routes.iter().map(|route| route.addr));
+            }
+
+            for addr in client_addrs {
+                if is_same_addr(&server_addr, &addr) {
+                    return Err(ConfigError::UdpLoop(addr));
+                }
+            }
+        }
+
+        if let Some(server_path) = &config.unix_server_path {
+            if config.unix_client_paths.contains(server_path) {
+                return Err(ConfigError::UnixLoop(server_path.clone()));
+            }
+        }
+
+        let mut server_addrs: Vec<SocketAddr> = Vec::new();
+        for addr in [
+            config.tcp_server_addr,
+            config.websocket_addr,
+            config.http_addr,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if server_addrs.iter().any(|other| is_same_addr(other, &addr)) {
+                return Err(ConfigError::DuplicateAddr(addr));
+            }
+            server_addrs.push(addr);
+        }
+
+        Ok(())
+    }
+
+    /// Validates the configuration and launches the node with all enabled subsystems.
+    pub async fn spawn(self) -> Result<Node, BuildError> {
+        self.validate()?;
+
+        let private_key = self.private_key.unwrap_or_default();
+        Node::spawn(private_key, self.config)
+            .await
+            .map_err(BuildError::Spawn)
+    }
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns true if data sent to the second address would be received by a socket bound to the
+/// first one.
+///
+/// Random ports (0) never match.
+fn is_same_addr(bind_addr: &SocketAddr, addr: &SocketAddr) -> bool {
+    bind_addr.port() != 0
+        && bind_addr.port() == addr.port()
+        && (bind_addr.ip() == addr.ip() || bind_addr.ip().is_unspecified())
+}
+
+/// Invalid node configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Option can only be used together with another one.
+    Requires {
+        option: &'static str,
+        requires: &'static str,
+    },
+
+    /// Options can't be used together.
+    Conflicts {
+        option: &'static str,
+        conflicts_with: &'static str,
+    },
+
+    /// Data sent to this address would be received by our own UDP server again.
+    UdpLoop(SocketAddr),
+
+    /// Data sent to this path would be received by our own Unix socket again.
+    UnixLoop(PathBuf),
+
+    /// Multiple servers are configured with the same address.
+    DuplicateAddr(SocketAddr),
+
+    /// Publish rate and burst need to be larger than zero.
+    InvalidPublishLimit,
+
+    /// Operations and bytes per author need to be larger than zero, leave them out for no limit.
+    InvalidAuthorRateLimit,
+
+    /// Proof-of-work difficulty is zero or so high that publishing would never finish.
+    InvalidPowDifficulty(u8),
+
+    /// Max. message size is zero or larger than a UDP datagram can be.
+    InvalidMaxMessageSize(usize),
+
+    /// Senders and subscriptions would expire right away.
+    InvalidSenderExpiry,
+
+    /// The MQTT mappings would make the node join more topics than it can.
+    TooManyTopics(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Requires { option, requires } => {
+                write!(f, "{option} can only be used together with {requires}")
+            }
+            ConfigError::Conflicts {
+                option,
+                conflicts_with,
+            } => write!(f, "{option} can't be used together with {conflicts_with}"),
+            ConfigError::UdpLoop(addr) => write!(
+                f,
+                "udp client {addr} is the udp server itself, messages would be sent in a loop"
+            ),
+            ConfigError::UnixLoop(path) => write!(
+                f,
+                "unix client {} is the unix server itself, messages would be sent in a loop",
+                path.display()
+            ),
+            ConfigError::DuplicateAddr(addr) => {
+                write!(f, "multiple servers are configured with address {addr}")
+            }
+            ConfigError::InvalidPublishLimit => {
+                write!(f, "publish rate and burst need to be larger than zero")
+            }
+            ConfigError::InvalidAuthorRateLimit => {
+                write!(f, "author rate limits need to be larger than zero")
+            }
+            ConfigError::InvalidPowDifficulty(difficulty) => write!(
+                f,
+                "proof-of-work difficulty {difficulty} needs to be between 1 and {MAX_POW_DIFFICULTY}"
+            ),
+            ConfigError::InvalidMaxMessageSize(size) => write!(
+                f,
+                "max. message size of {size} bytes needs to be between 1 and {MAX_DATAGRAM_SIZE}"
+            ),
+            ConfigError::InvalidSenderExpiry => {
+                write!(f, "sender expiry needs to be larger than zero")
+            }
+            ConfigError::TooManyTopics(count) => write!(
+                f,
+                "mqtt mappings join {count} topics, a node joins at most {MAX_TOPICS}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Error when launching a node.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The configuration is invalid, nothing was launched.
+    Config(ConfigError),
+
+    /// A subsystem could not be launched, for example because an address was already in use.
+    Spawn(anyhow::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Config(err) => write!(f, "invalid configuration: {err}"),
+            BuildError::Spawn(err) => write!(f, "could not launch node: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<ConfigError> for BuildError {
+    fn from(err: ConfigError) -> Self {
+        BuildError::Config(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use crate::mqtt::{MqttConfig, MqttMapping};
+    use crate::node::MAX_TOPICS;
+    use crate::rate_limit::{LimitPolicy, PublishLimit};
+    use crate::topic::Topic;
+    use crate::udp::MAX_DATAGRAM_SIZE;
+
+    use super::{is_same_addr, ConfigError, NodeBuilder};
+
+    fn addr(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn same_addr() {
+        assert!(is_same_addr(
+            &addr("127.0.0.1:4000"),
+            &addr("127.0.0.1:4000")
+        ));
+        assert!(is_same_addr(
+            &addr("0.0.0.0:4000"),
+            &addr("192.168.1.2:4000")
+        ));
+        assert!(is_same_addr(&addr("[::]:4000"), &addr("[::1]:4000")));
+        assert!(!is_same_addr(
+            &addr("127.0.0.1:4000"),
+            &addr("127.0.0.1:4001")
+        ));
+        assert!(!is_same_addr(
+            &addr("127.0.0.1:4000"),
+            &addr("127.0.0.2:4000")
+        ));
+        assert!(!is_same_addr(
+            &addr("192.168.1.2:4000"),
+            &addr("0.0.0.0:4000")
+        ));
+        assert!(!is_same_addr(&addr("127.0.0.1:0"), &addr("127.0.0.1:0")));
+    }
+
+    #[test]
+    fn requires() {
+        let builder = NodeBuilder::new().udp_reply_to_senders(Duration::from_secs(60));
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::Requires {
+                option: "reply to senders",
+                requires: "udp server",
+            })
+        );
+
+        let builder = NodeBuilder::new().allowed_origins(vec!["https://example.org".into()]);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::Requires {
+                option: "allowed origins",
+                requires: "websocket or http server",
+            })
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        let builder = NodeBuilder::new()
+            .udp(addr("127.0.0.1:4000"), Vec::new())
+            .udp_reply_to_senders(Duration::from_secs(60))
+            .udp_multicast(addr("239.0.0.1:4001"), 1, None);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::Conflicts {
+                option: "reply to senders",
+                conflicts_with: "udp multicast",
+            })
+        );
+    }
+
+    #[test]
+    fn udp_loop() {
+        let builder = NodeBuilder::new().udp(addr("0.0.0.0:4000"), vec![addr("127.0.0.1:4000")]);
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::UdpLoop(addr("127.0.0.1:4000")))
+        );
+
+        let builder = NodeBuilder::new().udp(addr("127.0.0.1:4000"), vec![addr("127.0.0.1:4001")]);
+        assert_eq!(builder.validate(), Ok(()));
+    }
+
+    #[test]
+    fn unix_loop() {
+        let path = PathBuf::from("/tmp/meshpit.sock");
+        let builder = NodeBuilder::new().unix(path.clone(), vec![path.clone()]);
+        assert_eq!(builder.validate(), Err(ConfigError::UnixLoop(path)));
+    }
+
+    #[test]
+    fn duplicate_addr() {
+        let builder = NodeBuilder::new()
+            .tcp(addr("127.0.0.1:4000"))
+            .websocket(addr("0.0.0.0:4001"))
+            .http(addr("127.0.0.1:4001"));
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::DuplicateAddr(addr("127.0.0.1:4001")))
+        );
+    }
+
+    #[test]
+    fn invalid_publish_limit() {
+        let builder = NodeBuilder::new().publish_limit(
+            Topic::new([0; 32]),
+            PublishLimit {
+                rate: 0,
+                burst: 10,
+                policy: LimitPolicy::Drop,
+            },
+        );
+        assert_eq!(builder.validate(), Err(ConfigError::InvalidPublishLimit));
+    }
+
+    #[test]
+    fn invalid_author_rate_limit() {
+        for (operations, bytes) in [(Some(0), None), (None, Some(0)), (Some(10), Some(0))] {
+            let builder = NodeBuilder::new().max_author_rate(operations, bytes);
+            assert_eq!(builder.validate(), Err(ConfigError::InvalidAuthorRateLimit));
+        }
+
+        let builder = NodeBuilder::new().max_author_rate(Some(1), Some(1));
+        assert_eq!(builder.validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_pow_difficulty() {
+        for difficulty in [0, 21] {
+            let builder = NodeBuilder::new().pow_difficulty(Topic::new([0; 32]), difficulty);
+            assert_eq!(
+                builder.validate(),
+                Err(ConfigError::InvalidPowDifficulty(difficulty))
+            );
+        }
+
+        let builder = NodeBuilder::new().pow_difficulty(Topic::new([0; 32]), 16);
+        assert_eq!(builder.validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_max_message_size() {
+        for size in [0, MAX_DATAGRAM_SIZE + 1, usize::MAX] {
+            let builder = NodeBuilder::new().max_message_size(size);
+            assert_eq!(
+                builder.validate(),
+                Err(ConfigError::InvalidMaxMessageSize(size))
+            );
+        }
+
+        for size in [1, MAX_DATAGRAM_SIZE] {
+            let builder = NodeBuilder::new().max_message_size(size);
+            assert_eq!(builder.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn separate_expiries() {
+        let builder = NodeBuilder::new()
+            .udp(addr("127.0.0.1:4000"), Vec::new())
+            .udp_reply_to_senders(Duration::from_secs(10))
+            .udp_subscriptions(Duration::from_secs(60));
+        assert_eq!(builder.config().udp_sender_expiry, Duration::from_secs(10));
+        assert_eq!(
+            builder.config().udp_subscription_expiry,
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn invalid_sender_expiry() {
+        let builder = NodeBuilder::new()
+            .udp(addr("127.0.0.1:4000"), Vec::new())
+            .udp_reply_to_senders(Duration::ZERO);
+        assert_eq!(builder.validate(), Err(ConfigError::InvalidSenderExpiry));
+
+        let builder = NodeBuilder::new()
+            .udp(addr("127.0.0.1:4000"), Vec::new())
+            .udp_subscriptions(Duration::ZERO);
+        assert_eq!(builder.validate(), Err(ConfigError::InvalidSenderExpiry));
+    }
+
+    #[test]
+    fn too_many_mqtt_topics() {
+        let mqtt = |count: usize| MqttConfig {
+            broker_host: "localhost".into(),
+            broker_port: 1883,
+            mappings: (0..count)
+                .map(|index| MqttMapping::from_str(&format!("sensors/{index}=sensors-{index}")))
+                .collect::<Result<_, _>>()
+                .unwrap(),
+        };
+
+        // The node's topic is joined as well.
+        let builder = NodeBuilder::new().mqtt(mqtt(MAX_TOPICS));
+        assert_eq!(
+            builder.validate(),
+            Err(ConfigError::TooManyTopics(MAX_TOPICS + 1))
+        );
+
+        let builder = NodeBuilder::new().mqtt(mqtt(MAX_TOPICS - 1));
+        assert_eq!(builder.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, Sink};
+use p2panda_core::{Hash, PublicKey};
+use p2panda_net::Network;
+use p2panda_sync::{FromSync, SyncError, SyncProtocol};
+use tokio::sync::broadcast;
+use tokio::time;
+
+use crate::topic::Topic;
+
+/// How often we check which peers we're connected to.
+const PEER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Peers we didn't exchange any data with for this long are considered inactive.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something which happened in the node, use this for monitoring or to build user interfaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Started exchanging data with another peer, directly or via a relay.
+    ///
+    /// This is a heuristic and not a connection event, the network doesn't report those. Peers are
+    /// considered active as long as we exchanged data with them within the last 30 seconds, checked
+    /// once per second. Quiet peers become idle even if they're still connected and peers we lost
+    /// stay active until the 30 seconds passed.
+    PeerActive { public_key: PublicKey },
+
+    /// Didn't exchange any data with another peer for 30 seconds or can't reach it anymore.
+    ///
+    /// See [`Event::PeerActive`], this does not mean that the connection was closed.
+    PeerIdle { public_key: PublicKey },
+
+    /// Joined the gossip overlay of the topic, from now on we receive new messages from other
+    /// peers.
+    GossipJoined { topic: Topic },
+
+    /// Started a sync session with another peer for the topic.
+    SyncStarted { topic: Topic },
+
+    /// Finished a sync session, the error is set when it failed.
+    SyncFinished { topic: Topic, error: Option<String> },
+
+    /// Published an operation with data from a local application.
+    OperationPublished {
+        topic: Topic,
+        hash: Hash,
+        seq_num: u64,
+    },
+
+    /// Received and stored an operation from another peer.
+    OperationReceived {
+        topic: Topic,
+        public_key: PublicKey,
+        hash: Hash,
+        seq_num: u64,
+    },
+
+    /// Rejected data from another peer, the author is not known when it could not be decoded.
+    OperationRejected {
+        public_key: Option<PublicKey>,
+        reason: String,
+    },
+
+    /// Blocked all further data from an author who forked their log.
+    AuthorBlocked { public_key: PublicKey },
+
+    /// A local bridge failed to receive or deliver data.
+    BridgeError { bridge: &'static str, error: String },
+
+    /// A background task stopped with an error while the node was running.
+    TaskFailed { task: &'static str, error: String },
+}
+
+/// Reports an error of a local bridge.
+pub fn report_bridge_error(
+    events_tx: &broadcast::Sender<Event>,
+    bridge: &'static str,
+    error: &impl fmt::Display,
+) {
+    let _ = events_tx.send(Event::BridgeError {
+        bridge,
+        error: error.to_string(),
+    });
+}
+
+/// Wraps a sync protocol and reports when sync sessions start and finish.
+#[derive(Debug)]
+pub struct ObservedSync<P> {
+    protocol: Arc<P>,
+    events_tx: broadcast::Sender<Event>,
+}
+
+impl<P> ObservedSync<P> {
+    pub fn new(protocol: P, events_tx: broadcast::Sender<Event>) -> Self {
+        Self {
+            protocol: Arc::new(protocol),
+            events_tx,
+        }
+    }
+}
+
+#[async_trait]
+impl<'a, P> SyncProtocol<'a, Topic> for ObservedSync<P>
+where
+    P: for<'b> SyncProtocol<'b, Topic> + 'static,
+{
+    fn name(&self) -> &'static str {
+        self.protocol.name()
+    }
+
+    async fn initiate(
+        self: Arc<Self>,
+        topic_query: Topic,
+        tx: Box<&'a mut (dyn AsyncWrite + Send + Unpin)>,
+        rx: Box<&'a mut (dyn AsyncRead + Send + Unpin)>,
+        app_tx: Box<&'a mut (dyn Sink<FromSync<Topic>, Error = SyncError> + Send + Unpin)>,
+    ) -> Result<(), SyncError> {
+        let (tx, rx) = (*tx, *rx);
+        let mut app_tx = HandshakeSink::new(*app_tx, self.events_tx.clone());
+        let result = self
+            .protocol
+            .clone()
+            .initiate(
+                topic_query,
+                Box::new(&mut *tx),
+                Box::new(&mut *rx),
+                Box::new(&mut app_tx),
+            )
+            .await;
+        app_tx.finish(&result);
+        result
+    }
+
+    async fn accept(
+        self: Arc<Self>,
+        tx: Box<&'a mut (dyn AsyncWrite + Send + Unpin)>,
+        rx: Box<&'a mut (dyn AsyncRead + Send + Unpin)>,
+        app_tx: Box<&'a mut (dyn Sink<FromSync<Topic>, Error = SyncError> + Send + Unpin)>,
+    ) -> Result<(), SyncError> {
+        let (tx, rx) = (*tx, *rx);
+        let mut app_tx = HandshakeSink::new(*app_tx, self.events_tx.clone());
+        let result = self
+            .protocol
+            .clone()
+            .accept(
+                Box::new(&mut *tx),
+                Box::new(&mut *rx),
+                Box::new(&mut app_tx),
+            )
+            .await;
+        app_tx.finish(&result);
+        result
+    }
+}
+
+/// Forwards everything to the application layer and takes note of the topic as soon as the sync
+/// handshake succeeded.
+struct HandshakeSink<'a> {
+    inner: &'a mut (dyn Sink<FromSync<Topic>, Error = SyncError> + Send + Unpin),
+    topic: Option<Topic>,
+    events_tx: broadcast::Sender<Event>,
+}
+
+impl<'a> HandshakeSink<'a> {
+    fn new(
+        inner: &'a mut (dyn Sink<FromSync<Topic>, Error = SyncError> + Send + Unpin),
+        events_tx: broadcast::Sender<Event>,
+    ) -> Self {
+        Self {
+            inner,
+            topic: None,
+            events_tx,
+        }
+    }
+
+    /// Reports the end of the session, if it got past the handshake.
+    fn finish(self, result: &Result<(), SyncError>) {
+        if let Some(topic) = self.topic {
+            let _ = self.events_tx.send(Event::SyncFinished {
+                topic,
+                error: result.as_ref().err().map(ToString::to_string),
+            });
+        }
+    }
+}
+
+impl Sink<FromSync<Topic>> for HandshakeSink<'_> {
+    type Error = SyncError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: FromSync<Topic>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        if let FromSync::HandshakeSuccess(topic) = &item {
+            this.topic = Some(topic.clone());
+            let _ = this.events_tx.send(Event::SyncStarted {
+                topic: topic.clone(),
+            });
+        }
+        Pin::new(&mut *this.inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Reports peers which become active or idle.
+///
+/// The network doesn't tell us about connections or gossip neighbours, so we regularly look at all
+/// peers we know about and consider them active when we've recently exchanged data with them. This
+/// does not follow the state of the underlying connections.
+pub async fn watch_peers(network: Network<Topic>, events_tx: broadcast::Sender<Event>) {
+    let mut peers = ActivePeers::default();
+    let mut interval = time::interval(PEER_POLL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let active = network
+            .endpoint()
+            .remote_info_iter()
+            .filter(|info| is_active(info.has_send_address(), info.last_used))
+            .filter_map(|info| PublicKey::from_bytes(info.node_id.as_bytes()).ok())
+            .collect();
+
+        for event in peers.update(active) {
+            let _ = events_tx.send(event);
+        }
+    }
+}
+
+/// Returns true if we know how to reach the peer and exchanged data with it recently.
+fn is_active(reachable: bool, last_used: Option<Duration>) -> bool {
+    reachable && last_used.is_some_and(|last_used| last_used < PEER_TIMEOUT)
+}
+
+/// Peers which are currently active.
+#[derive(Debug, Default)]
+struct ActivePeers(HashSet<PublicKey>);
+
+impl ActivePeers {
+    /// Replaces the active peers and returns events for all peers which became active or idle.
+    fn update(&mut self, active: HashSet<PublicKey>) -> Vec<Event> {
+        let mut events: Vec<Event> = active
+            .difference(&self.0)
+            .map(|public_key| Event::PeerActive {
+                public_key: *public_key,
+            })
+            .collect();
+        events.extend(
+            self.0
+                .difference(&active)
+                .map(|public_key| Event::PeerIdle {
+                    public_key: *public_key,
+                }),
+        );
+
+        self.0 = active;
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use p2panda_core::{PrivateKey, PublicKey};
+
+    use super::{is_active, ActivePeers, Event, PEER_TIMEOUT};
+
+    fn peers(public_keys: &[PublicKey]) -> HashSet<PublicKey> {
+        public_keys.iter().copied().collect()
+    }
+
+    #[test]
+    fn peer_transitions() {
+        let a = PrivateKey::new().public_key();
+        let b = PrivateKey::new().public_key();
+        let mut active = ActivePeers::default();
+
+        assert_eq!(
+            active.update(peers(&[a])),
+            vec![Event::PeerActive { public_key: a }]
+        );
+        assert_eq!(
+            active.update(peers(&[a, b])),
+            vec![Event::PeerActive { public_key: b }]
+        );
+        assert_eq!(active.update(peers(&[a, b])), vec![]);
+        assert_eq!(
+            active.update(peers(&[b])),
+            vec![Event::PeerIdle { public_key: a }]
+        );
+
+        // Idle peers become active again as soon as we exchange data with them.
+        let events = active.update(peers(&[a]));
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&Event::PeerActive { public_key: a }));
+        assert!(events.contains(&Event::PeerIdle { public_key: b }));
+
+        assert_eq!(
+            active.update(peers(&[])),
+            vec![Event::PeerIdle { public_key: a }]
+        );
+    }
+
+    #[test]
+    fn active_peers() {
+        let recently = Some(Duration::from_secs(1));
+        assert!(is_active(true, recently));
+        assert!(!is_active(false, recently));
+
+        // Quiet peers are idle even if we can still reach them.
+        assert!(!is_active(true, Some(PEER_TIMEOUT)));
+        assert!(!is_active(true, None));
+    }
+}
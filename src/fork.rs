@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use p2panda_core::{Extension, Hash, Header, PublicKey};
+use p2panda_store::{LocalLogStore, MemoryStore};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tracing::{debug, warn};
+
+use crate::events::Event;
+use crate::operation::Extensions;
+use crate::topic::LogId;
+
+/// Number of accepted operations per log we remember while they wait to be ingested, matches the
+/// size of the ingest buffer for out-of-order operations.
+const MAX_PENDING_OPERATIONS: usize = 128;
+
+/// Hashes of accepted operations which are not ingested yet, by author log and sequence number.
+type PendingOperations = HashMap<(PublicKey, LogId), BTreeMap<u64, Hash>>;
+
+/// Detects forks in the append-only logs of other authors.
+///
+/// Logs are single-writer chains, every sequence number can only be used once per author and log.
+/// Two different operations with the same sequence number or an operation which doesn't point at
+/// the previous one we know about indicate a buggy or malicious peer.
+///
+/// Operations arriving out of order wait for the missing ones before they are ingested, so we also
+/// compare against the operations we've accepted but which are not in the store yet.
+#[derive(Clone, Debug)]
+pub struct ForkDetector {
+    store: MemoryStore<LogId, Extensions>,
+    pending: Arc<Mutex<PendingOperations>>,
+    block_authors: bool,
+    blocked: Arc<RwLock<HashSet<PublicKey>>>,
+    events_tx: broadcast::Sender<Event>,
+}
+
+impl ForkDetector {
+    pub fn new(
+        store: MemoryStore<LogId, Extensions>,
+        block_authors: bool,
+        events_tx: broadcast::Sender<Event>,
+    ) -> Self {
+        Self {
+            store,
+            pending: Arc::default(),
+            block_authors,
+            blocked: Arc::new(RwLock::new(HashSet::new())),
+            events_tx,
+        }
+    }
+
+    /// Returns all authors we've blocked because they forked their log.
+    pub async fn blocked_authors(&self) -> Vec<PublicKey> {
+        self.blocked.read().await.iter().copied().collect()
+    }
+
+    /// Returns an error if the operation forks the author's log and should not be ingested.
+    ///
+    /// When blocking is enabled, all further operations of an author who forked their log are
+    /// rejected as well.
+    pub async fn check(&self, header: &Header<Extensions>) -> Result<()> {
+        if self.blocked.read().await.contains(&header.public_key) {
+            debug!(
+                public_key = %header.public_key,
+                seq_num = header.seq_num,
+                "drop operation from blocked author"
+            );
+            bail!("author is blocked");
+        }
+
+        let Some(log_id): Option<LogId> = header.extract() else {
+            return Ok(());
+        };
+
+        let existing = match self
+            .hash_at(&header.public_key, &log_id, header.seq_num)
+            .await
+        {
+            Some(existing) if existing == header.hash() => return Ok(()),
+            Some(existing) => existing,
+            None => {
+                let previous = match header.seq_num.checked_sub(1) {
+                    Some(seq_num) => self.hash_at(&header.public_key, &log_id, seq_num).await,
+                    None => None,
+                };
+
+                match previous {
+                    Some(previous) if header.backlink != Some(previous) => previous,
+                    _ => {
+                        self.remember_pending(header, log_id).await;
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        warn!(
+            public_key = %header.public_key,
+            log_id = hex::encode(log_id),
+            seq_num = header.seq_num,
+            existing = %existing,
+            received = %header.hash(),
+            "detected fork in author log"
+        );
+
+        if self.block_authors && self.blocked.write().await.insert(header.public_key) {
+            warn!(public_key = %header.public_key, "block author");
+            let _ = self.events_tx.send(Event::AuthorBlocked {
+                public_key: header.public_key,
+            });
+        }
+
+        bail!("detected fork in author log")
+    }
+
+    /// Returns the hash of the operation at the given position of the author's log, if we have it
+    /// or accepted it already.
+    async fn hash_at(&self, public_key: &PublicKey, log_id: &LogId, seq_num: u64) -> Option<Hash> {
+        if let Some(existing) = self.operation_at(public_key, log_id, seq_num).await {
+            return Some(existing.hash());
+        }
+
+        self.pending
+            .lock()
+            .await
+            .get(&(*public_key, *log_id))
+            .and_then(|log| log.get(&seq_num))
+            .copied()
+    }
+
+    /// Remembers an accepted operation until it is in the store, it might need to wait for missing
+    /// operations before it is ingested.
+    async fn remember_pending(&self, header: &Header<Extensions>, log_id: LogId) {
+        let Ok(latest_operation) = self
+            .store
+            .latest_operation(&header.public_key, &log_id)
+            .await;
+        let next_seq_num =
+            latest_operation.map_or(0, |(latest, _)| latest.seq_num.saturating_add(1));
+
+        let mut pending = self.pending.lock().await;
+        let log = pending.entry((header.public_key, log_id)).or_default();
+
+        // Forget about operations which were ingested in the meantime.
+        *log = log.split_off(&next_seq_num);
+        if header.seq_num >= next_seq_num {
+            log.insert(header.seq_num, header.hash());
+        }
+        while log.len() > MAX_PENDING_OPERATIONS {
+            log.pop_last();
+        }
+
+        if log.is_empty() {
+            pending.remove(&(header.public_key, log_id));
+        }
+    }
+
+    /// Returns the operation at the given position of the author's log, if we have it.
+    async fn operation_at(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        seq_num: u64,
+    ) -> Option<Header<Extensions>> {
+        let Ok(latest_operation) = self.store.latest_operation(public_key, log_id).await;
+
+        match latest_operation {
+            Some((latest, _)) if latest.seq_num == seq_num => Some(latest),
+            Some((latest, _)) if latest.seq_num > seq_num => {
+                let Ok(log) = self.store.get_log(public_key, log_id, Some(seq_num)).await;
+                log.and_then(|log| log.into_iter().next())
+                    .map(|(header, _)| header)
+                    .filter(|existing| existing.seq_num == seq_num)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Header, PrivateKey};
+    use p2panda_store::MemoryStore;
+    use p2panda_stream::operation::ingest_operation;
+    use tokio::sync::broadcast;
+
+    use crate::operation::{create_operation, Extensions, OperationOptions};
+    use crate::topic::LogId;
+
+    use super::ForkDetector;
+
+    const LOG_ID: LogId = [1; 32];
+
+    async fn create(
+        store: &mut MemoryStore<LogId, Extensions>,
+        private_key: &PrivateKey,
+        payload: &[u8],
+    ) -> (Header<Extensions>, Option<Body>) {
+        create_operation(
+            store,
+            LOG_ID,
+            private_key,
+            Some(payload),
+            OperationOptions::default(),
+        )
+        .await
+    }
+
+    async fn ingest(
+        store: &mut MemoryStore<LogId, Extensions>,
+        (header, body): (Header<Extensions>, Option<Body>),
+    ) {
+        let header_bytes = header.to_bytes();
+        ingest_operation(store, header, body, header_bytes, &LOG_ID, false)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn accept_valid_operations() {
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let (events_tx, _) = broadcast::channel(16);
+        let detector = ForkDetector::new(store.clone(), false, events_tx);
+
+        let first = create(&mut store, &private_key, b"first").await;
+        assert!(detector.check(&first.0).await.is_ok());
+        ingest(&mut store, first.clone()).await;
+
+        // Receiving the same operation again is not a fork.
+        assert!(detector.check(&first.0).await.is_ok());
+
+        let second = create(&mut store, &private_key, b"second").await;
+        assert!(detector.check(&second.0).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn detect_same_seq_num_with_different_hash() {
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let mut fork_store = MemoryStore::new();
+        let (events_tx, _) = broadcast::channel(16);
+        let detector = ForkDetector::new(store.clone(), false, events_tx);
+
+        let operation = create(&mut store, &private_key, b"original").await;
+        ingest(&mut store, operation).await;
+
+        let fork = create(&mut fork_store, &private_key, b"fork").await;
+        assert_eq!(fork.0.seq_num, 0);
+        assert!(detector.check(&fork.0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detect_backlink_mismatch() {
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let mut fork_store = MemoryStore::new();
+        let (events_tx, _) = broadcast::channel(16);
+        let detector = ForkDetector::new(store.clone(), false, events_tx);
+
+        let operation = create(&mut store, &private_key, b"original").await;
+        ingest(&mut store, operation).await;
+
+        // The second operation of the forked log points at an operation we don't know.
+        let fork = create(&mut fork_store, &private_key, b"fork").await;
+        ingest(&mut fork_store, fork).await;
+        let next = create(&mut fork_store, &private_key, b"next").await;
+        assert_eq!(next.0.seq_num, 1);
+        assert!(detector.check(&next.0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detect_forks_arriving_out_of_order() {
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let mut fork_store = MemoryStore::new();
+        let (events_tx, _) = broadcast::channel(16);
+
+        // Both logs share the first operation and diverge afterwards.
+        let first = create(&mut store, &private_key, b"first").await;
+        ingest(&mut store, first.clone()).await;
+        ingest(&mut fork_store, first.clone()).await;
+        let operation = create(&mut store, &private_key, b"original").await;
+        let fork = create(&mut fork_store, &private_key, b"fork").await;
+        assert_eq!(fork.0.seq_num, 1);
+
+        // We didn't receive the first operation yet, so none of them can be ingested.
+        let detector = ForkDetector::new(MemoryStore::new(), false, events_tx);
+        assert!(detector.check(&operation.0).await.is_ok());
+        assert!(detector.check(&operation.0).await.is_ok());
+        assert!(detector.check(&fork.0).await.is_err());
+        assert!(detector.check(&first.0).await.is_ok());
+
+        // Operations pointing at a pending fork are detected as well.
+        let next = create(&mut fork_store, &private_key, b"next").await;
+        assert!(detector.check(&next.0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn block_authors() {
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let mut fork_store = MemoryStore::new();
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        let detector = ForkDetector::new(store.clone(), true, events_tx);
+
+        let operation = create(&mut store, &private_key, b"original").await;
+        ingest(&mut store, operation).await;
+
+        let fork = create(&mut fork_store, &private_key, b"fork").await;
+        assert!(detector.check(&fork.0).await.is_err());
+        assert_eq!(
+            detector.blocked_authors().await,
+            vec![private_key.public_key()]
+        );
+        assert!(events_rx.try_recv().is_ok());
+
+        // Valid operations of blocked authors are rejected as well.
+        let next = create(&mut store, &private_key, b"next").await;
+        assert!(detector.check(&next.0).await.is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use p2panda_core::PublicKey;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::message::Message;
+use crate::topic::LogId;
+
+/// Maximum number of operations a payload can be split into.
+pub const MAX_FRAGMENTS: usize = 1024;
+
+/// Maximum number of incomplete payloads we buffer per author.
+const MAX_PARTIALS_PER_AUTHOR: usize = 4;
+
+/// Maximum number of incomplete payloads we buffer for all authors.
+const MAX_PARTIALS: usize = 256;
+
+/// Maximum number of bytes we buffer for all incomplete payloads.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Incomplete payloads are dropped if their next fragment doesn't arrive within this time.
+const PARTIAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Position of an operation in a payload which was split into multiple operations.
+///
+/// Fragments of one payload are always published in a row, they are linked by their consecutive
+/// sequence numbers in the author's log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub index: u32,
+    pub count: u32,
+}
+
+/// Splits a payload into chunks of the given size.
+pub fn split(payload: &[u8], chunk_size: usize) -> Vec<(&[u8], Option<Fragment>)> {
+    if payload.len() <= chunk_size {
+        return vec![(payload, None)];
+    }
+
+    let chunks: Vec<&[u8]> = payload.chunks(chunk_size).collect();
+    let count = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            (
+                chunk,
+                Some(Fragment {
+                    index: index as u32,
+                    count,
+                }),
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Partial {
+    message: Message,
+    next_index: u32,
+    count: u32,
+    updated_at: Instant,
+}
+
+/// Collects fragments from other authors and puts their payloads back together.
+///
+/// We only keep one incomplete payload per author and log, fragments arriving in an unexpected
+/// order invalidate it. The number of incomplete payloads per author, the bytes buffered for all
+/// of them and the time we wait for the next fragment are limited, so peers can't make us buffer
+/// payloads which are never completed.
+#[derive(Debug)]
+pub struct Reassembler {
+    partials: HashMap<(PublicKey, LogId), Partial>,
+    buffered_bytes: usize,
+    max_partials_per_author: usize,
+    max_partials: usize,
+    max_buffered_bytes: usize,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self {
+            partials: HashMap::new(),
+            buffered_bytes: 0,
+            max_partials_per_author: MAX_PARTIALS_PER_AUTHOR,
+            max_partials: MAX_PARTIALS,
+            max_buffered_bytes: MAX_BUFFERED_BYTES,
+            timeout: PARTIAL_TIMEOUT,
+        }
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles a received message and returns it as soon as it is complete.
+    ///
+    /// The metadata of a reassembled message is taken from its first fragment. Fails when the
+    /// fragment was dropped, because it is invalid, arrived in an unexpected order or exceeds the
+    /// limits.
+    pub fn push(
+        &mut self,
+        log_id: LogId,
+        fragment: Option<Fragment>,
+        message: Message,
+    ) -> Result<Option<Message>> {
+        let Some(fragment) = fragment else {
+            return Ok(Some(message));
+        };
+
+        let now = Instant::now();
+        self.evict_expired(now);
+
+        let key = (message.public_key, log_id);
+
+        if fragment.count as usize > MAX_FRAGMENTS || fragment.index >= fragment.count {
+            self.remove(&key);
+            bail!(
+                "invalid fragment index {} for {} fragments",
+                fragment.index,
+                fragment.count
+            );
+        }
+
+        if fragment.index == 0 {
+            if self.remove(&key) {
+                warn!(public_key = %message.public_key, "drop incomplete fragmented payload");
+            }
+
+            let author_partials = self
+                .partials
+                .keys()
+                .filter(|(public_key, _)| public_key == &message.public_key)
+                .count();
+            if author_partials >= self.max_partials_per_author {
+                bail!("author exceeded limit of incomplete fragmented payloads");
+            }
+
+            if self.partials.len() >= self.max_partials
+                || self.buffered_bytes + message.payload.len() > self.max_buffered_bytes
+            {
+                bail!("too many incomplete fragmented payloads");
+            }
+
+            self.buffered_bytes += message.payload.len();
+            self.partials.insert(
+                key,
+                Partial {
+                    message,
+                    next_index: 1,
+                    count: fragment.count,
+                    updated_at: now,
+                },
+            );
+        } else {
+            let Some(partial) = self.partials.get(&key) else {
+                bail!(
+                    "fragment index {} of {} fragments without beginning",
+                    fragment.index,
+                    fragment.count
+                );
+            };
+
+            // Sequence numbers come from other peers, don't let them overflow.
+            let expected_seq_num = partial
+                .message
+                .seq_num
+                .saturating_add(fragment.index as u64);
+            if partial.next_index != fragment.index
+                || partial.count != fragment.count
+                || message.seq_num != expected_seq_num
+            {
+                self.remove(&key);
+                bail!(
+                    "fragment index {} of {} fragments arrived out of order, drop incomplete \
+                     payload",
+                    fragment.index,
+                    fragment.count
+                );
+            }
+
+            if self.buffered_bytes + message.payload.len() > self.max_buffered_bytes {
+                self.remove(&key);
+                bail!("too many incomplete fragmented payloads, drop incomplete payload");
+            }
+
+            self.buffered_bytes += message.payload.len();
+            let partial = self.partials.get_mut(&key).expect("partial payload exists");
+            partial.message.payload.extend_from_slice(&message.payload);
+            partial.next_index += 1;
+            partial.updated_at = now;
+        }
+
+        let partial = self.partials.get(&key).expect("partial payload exists");
+        if partial.next_index == partial.count {
+            let partial = self.partials.remove(&key).expect("partial payload exists");
+            self.buffered_bytes -= partial.message.payload.len();
+            debug!(
+                public_key = %partial.message.public_key,
+                count = partial.count,
+                len = partial.message.payload.len(),
+                "reassembled fragmented payload"
+            );
+            Ok(Some(partial.message))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Removes an incomplete payload, returns true if there was one.
+    fn remove(&mut self, key: &(PublicKey, LogId)) -> bool {
+        match self.partials.remove(key) {
+            Some(partial) => {
+                self.buffered_bytes -= partial.message.payload.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops all incomplete payloads which didn't receive their next fragment in time.
+    fn evict_expired(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let mut evicted_bytes = 0;
+        self.partials.retain(|(public_key, _), partial| {
+            if now.saturating_duration_since(partial.updated_at) < timeout {
+                return true;
+            }
+
+            warn!(public_key = %public_key, "drop expired fragmented payload");
+            evicted_bytes += partial.message.payload.len();
+            false
+        });
+        self.buffered_bytes -= evicted_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use p2panda_core::{Hash, PrivateKey, PublicKey};
+
+    use crate::message::Message;
+    use crate::topic::{LogId, Topic};
+
+    use super::{split, Fragment, Reassembler};
+
+    const LOG_ID: LogId = [0; 32];
+
+    /// Splits the payload and turns the chunks into messages with consecutive sequence numbers.
+    fn fragments(
+        public_key: PublicKey,
+        payload: &[u8],
+        chunk_size: usize,
+    ) -> Vec<(Option<Fragment>, Message)> {
+        split(payload, chunk_size)
+            .into_iter()
+            .enumerate()
+            .map(|(seq_num, (chunk, fragment))| {
+                let message = Message {
+                    public_key,
+                    seq_num: seq_num as u64,
+                    timestamp: 0,
+                    hash: Hash::new(chunk),
+                    topic: Topic::new([0; 32]),
+                    content_type: None,
+                    subject: None,
+                    payload: chunk.to_vec(),
+                };
+                (fragment, message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_payloads() {
+        let parts = split(b"hello", 5);
+        assert_eq!(parts, vec![(&b"hello"[..], None)]);
+
+        let parts = split(b"hello", 2);
+        assert_eq!(parts.len(), 3);
+        assert_eq!(
+            parts[0],
+            (&b"he"[..], Some(Fragment { index: 0, count: 3 }))
+        );
+        assert_eq!(parts[2], (&b"o"[..], Some(Fragment { index: 2, count: 3 })));
+    }
+
+    #[test]
+    fn reassemble_payloads() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let mut fragments = fragments(alice, b"hello, world", 5).into_iter();
+        let (fragment, message) = fragments.next().unwrap();
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+        let (fragment, message) = fragments.next().unwrap();
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+        let (fragment, message) = fragments.next().unwrap();
+        let message = reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(message.payload, b"hello, world");
+        assert_eq!(message.seq_num, 0);
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn pass_through_unfragmented_payloads() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let (fragment, message) = fragments(alice, b"hello", 5).remove(0);
+        let message = reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.payload, b"hello");
+    }
+
+    #[test]
+    fn drop_fragments_out_of_order() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let mut fragments = fragments(alice, b"hello, world", 5);
+        let (fragment, message) = fragments.remove(1);
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+
+        // Without the second fragment the remaining ones can't be reassembled.
+        let (fragment, message) = fragments.remove(0);
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+        let (fragment, message) = fragments.remove(0);
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn drop_duplicate_fragments() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let fragments = fragments(alice, b"hello, world", 5);
+        for (fragment, message) in fragments.iter().take(2) {
+            assert!(reassembler
+                .push(LOG_ID, *fragment, message.clone())
+                .unwrap()
+                .is_none());
+        }
+
+        let (fragment, message) = fragments[1].clone();
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert!(reassembler.partials.is_empty());
+
+        let (fragment, message) = fragments[2].clone();
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+    }
+
+    #[test]
+    fn drop_payload_with_missing_fragment() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let mut fragments = fragments(alice, b"hello, world", 5);
+        fragments.remove(1);
+        let (fragment, message) = fragments.remove(0);
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+        let (fragment, message) = fragments.remove(0);
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn drop_fragments_with_wrong_count() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let mut fragments = fragments(alice, b"hello, world", 5).into_iter();
+        let (fragment, message) = fragments.next().unwrap();
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+
+        let (_, message) = fragments.next().unwrap();
+        let fragment = Some(Fragment { index: 1, count: 2 });
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert!(reassembler.partials.is_empty());
+
+        // Fragments with an index beyond their count are invalid.
+        let (_, message) = fragments.next().unwrap();
+        let fragment = Some(Fragment { index: 3, count: 3 });
+        assert!(reassembler.push(LOG_ID, fragment, message.clone()).is_err());
+
+        let fragment = Some(Fragment {
+            index: u32::MAX,
+            count: u32::MAX,
+        });
+        assert!(reassembler.push(LOG_ID, fragment, message.clone()).is_err());
+
+        let fragment = Some(Fragment {
+            index: u32::MAX,
+            count: 3,
+        });
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+    }
+
+    #[test]
+    fn limit_partials_per_author() {
+        let alice = PrivateKey::new().public_key();
+        let bob = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+        reassembler.max_partials_per_author = 1;
+
+        let (fragment, message) = fragments(alice, b"hello", 2).remove(0);
+        assert!(reassembler
+            .push([1; 32], fragment, message)
+            .unwrap()
+            .is_none());
+        let (fragment, message) = fragments(alice, b"hello", 2).remove(0);
+        assert!(reassembler.push([2; 32], fragment, message).is_err());
+        assert_eq!(reassembler.partials.len(), 1);
+
+        // Other authors have their own limit.
+        let (fragment, message) = fragments(bob, b"hello", 2).remove(0);
+        assert!(reassembler
+            .push([2; 32], fragment, message)
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembler.partials.len(), 2);
+    }
+
+    #[test]
+    fn limit_buffered_bytes() {
+        let alice = PrivateKey::new().public_key();
+        let bob = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+        reassembler.max_buffered_bytes = 6;
+
+        let mut alice_fragments = fragments(alice, b"hello, world", 5).into_iter();
+        let (fragment, message) = alice_fragments.next().unwrap();
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+
+        let (fragment, message) = fragments(bob, b"hello", 2).remove(0);
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert_eq!(reassembler.partials.len(), 1);
+
+        // The next fragment exceeds the limit, so the whole payload is dropped.
+        let (fragment, message) = alice_fragments.next().unwrap();
+        assert!(reassembler.push(LOG_ID, fragment, message).is_err());
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+
+    #[test]
+    fn evict_expired_partials() {
+        let alice = PrivateKey::new().public_key();
+        let mut reassembler = Reassembler::new();
+
+        let (fragment, message) = fragments(alice, b"hello", 2).remove(0);
+        assert!(reassembler
+            .push(LOG_ID, fragment, message)
+            .unwrap()
+            .is_none());
+
+        reassembler.evict_expired(Instant::now());
+        assert_eq!(reassembler.partials.len(), 1);
+
+        reassembler.evict_expired(Instant::now() + reassembler.timeout + Duration::from_secs(1));
+        assert!(reassembler.partials.is_empty());
+        assert_eq!(reassembler.buffered_bytes, 0);
+    }
+}
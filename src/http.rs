@@ -0,0 +1,518 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use p2panda_net::TopicId;
+use p2panda_store::{LocalLogStore, MemoryStore};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::events::{report_bridge_error, Event as NodeEvent};
+use crate::fragment::Reassembler;
+use crate::message::{Message, OutgoingMessage};
+use crate::node::{join_topic, JoinRequest, PublishRequest, TooManyTopics, MAX_PUBLISH_SIZE};
+use crate::operation::{is_expired, Extensions};
+use crate::tasks::TaskGroup;
+use crate::topic::{AuthorStore, LogId, Topic};
+use crate::websocket::is_allowed_origin;
+
+/// Local HTTP API for scripts and web dashboards.
+///
+/// - `POST /topics/{topic}/messages` publishes the request body to the topic and returns the hash
+///   of the operation
+/// - `GET /topics/{topic}/messages?since={timestamp}&limit={count}` returns the latest stored
+///   messages of the topic
+/// - `GET /topics/{topic}/events` streams all incoming messages of the topic as Server-Sent Events
+///
+/// Topics are identified by their name, messages are returned as JSON envelopes. Reading a topic
+/// joins it, so its messages arrive from now on. Browsers can only use the API from allowed
+/// origins, see [`is_allowed_origin`].
+///
+/// Unlike the other local APIs this is not a [`Bridge`](crate::bridge::Bridge): every request
+/// publishes at most one message and is answered right away, received messages are only streamed
+/// to clients which asked for a topic instead of being delivered to everyone.
+#[derive(Clone, Debug)]
+pub struct HttpApi {
+    pub publish_tx: mpsc::Sender<PublishRequest>,
+    pub join_tx: mpsc::Sender<JoinRequest>,
+    pub messages_tx: broadcast::Sender<Message>,
+    pub events_tx: broadcast::Sender<NodeEvent>,
+    pub operation_store: MemoryStore<LogId, Extensions>,
+    pub author_store: AuthorStore,
+    pub allowed_origins: Vec<String>,
+}
+
+/// Maximum number of messages returned by `GET /topics/{topic}/messages`.
+const MAX_MESSAGES: usize = 1000;
+
+impl HttpApi {
+    pub fn spawn(self, listener: TcpListener, tasks: &TaskGroup) {
+        let events_tx = self.events_tx.clone();
+        let router = Router::new()
+            .route(
+                "/topics/{topic}/messages",
+                post(publish_message).get(get_messages),
+            )
+            .route("/topics/{topic}/events", get(stream_events))
+            .layer(axum::extract::DefaultBodyLimit::max(MAX_PUBLISH_SIZE))
+            .layer(Extension(tasks.token()))
+            .with_state(self);
+
+        tasks.spawn_graceful("http", |shutdown| async move {
+            let result = axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await;
+            if let Err(err) = &result {
+                error!("http server error: {err}");
+                report_bridge_error(&events_tx, "http", err);
+            }
+            Ok(result?)
+        });
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PublishQuery {
+    #[serde(default)]
+    ephemeral: bool,
+    ttl: Option<u64>,
+}
+
+async fn publish_message(
+    State(api): State<HttpApi>,
+    Path(topic): Path<String>,
+    Query(query): Query<PublishQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<PublishResponse>), ApiError> {
+    // Browsers send simple cross-origin requests without asking, so pages of other origins could
+    // publish in the name of this node.
+    check_origin(&api, &headers)?;
+
+    let topic = parse_topic(&topic)?;
+
+    let content_type = match headers.get(header::CONTENT_TYPE) {
+        Some(value) => Some(
+            value
+                .to_str()
+                .map_err(|_| ApiError::bad_request("invalid content type"))?
+                .to_owned(),
+        ),
+        None => None,
+    };
+
+    let message = OutgoingMessage {
+        topic: Some(topic),
+        ephemeral: query.ephemeral,
+        ttl: query.ttl,
+        content_type,
+        subject: None,
+        payload: body.to_vec(),
+    };
+    message
+        .validate()
+        .map_err(|err| ApiError::bad_request(&err.to_string()))?;
+
+    // Wait until the message was published, it might be held back by the publish rate limit.
+    let (reply_tx, reply_rx) = oneshot::channel();
+    api.publish_tx
+        .send(PublishRequest {
+            message,
+            reply_tx: Some(reply_tx),
+        })
+        .await
+        .map_err(|_| ApiError::unavailable())?;
+
+    let hash = match reply_rx.await {
+        Ok(Ok(hash)) => hash,
+        Ok(Err(err)) => return Err(ApiError::internal(&err.to_string())),
+        Err(_) => return Err(ApiError::too_many_requests()),
+    };
+
+    // Other peers receive the message in the background.
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(PublishResponse {
+            hash: hash.to_hex(),
+        }),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct PublishResponse {
+    /// Hash of the published operation, the first one if the payload was split.
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    /// Only return messages created at or after this UNIX timestamp (in seconds).
+    since: Option<u64>,
+
+    /// Maximum number of messages to return, only the latest ones are returned.
+    limit: Option<usize>,
+}
+
+async fn get_messages(
+    State(api): State<HttpApi>,
+    Path(topic): Path<String>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Message>>, ApiError> {
+    // Pages of other origins can't read the response, but they could make us join topics.
+    check_origin(&api, &headers)?;
+
+    let topic = parse_topic(&topic)?;
+    join(&api, &topic).await?;
+    let log_id: LogId = topic.id();
+    let since = query.since.unwrap_or_default();
+    let limit = query.limit.unwrap_or(MAX_MESSAGES).min(MAX_MESSAGES);
+
+    let mut messages = Vec::new();
+    let mut reassembler = Reassembler::new();
+
+    for public_key in api.author_store.authors(&topic).await.unwrap_or_default() {
+        let Ok(log) = api
+            .operation_store
+            .get_log(&public_key, &log_id, None)
+            .await;
+
+        for (header, body) in log.unwrap_or_default() {
+            let Some(body) = body else {
+                continue;
+            };
+
+            if is_expired(&header) {
+                continue;
+            }
+
+            let fragment = header
+                .extensions
+                .as_ref()
+                .and_then(|extensions| extensions.fragment());
+            let message = Message::from_operation(&header, &body);
+            // Skip fragments of payloads which are incomplete or were dropped during ingest.
+            let Ok(Some(message)) = reassembler.push(log_id, fragment, message) else {
+                continue;
+            };
+
+            if message.timestamp >= since {
+                messages.push(message);
+            }
+        }
+    }
+
+    messages.sort_by_key(|message| message.timestamp);
+    messages.drain(..messages.len().saturating_sub(limit));
+
+    Ok(Json(messages))
+}
+
+async fn stream_events(
+    State(api): State<HttpApi>,
+    Path(topic): Path<String>,
+    Extension(shutdown): Extension<CancellationToken>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    check_origin(&api, &headers)?;
+
+    let topic = parse_topic(&topic)?;
+    // Subscribe before joining the topic so we don't miss any messages.
+    let messages_rx = api.messages_tx.subscribe();
+    join(&api, &topic).await?;
+
+    let stream = BroadcastStream::new(messages_rx).filter_map(move |message| {
+        // Lagging clients skip messages.
+        let message = message.ok()?;
+        if message.topic != topic {
+            return None;
+        }
+
+        match Event::default().event("message").json_data(&message) {
+            Ok(event) => Some(Ok(event)),
+            Err(err) => {
+                error!("could not encode message envelope: {err}");
+                None
+            }
+        }
+    });
+
+    // End the stream when the node shuts down, otherwise the server waits for the client forever.
+    let stream = futures::StreamExt::take_until(stream, shutdown.cancelled_owned());
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn check_origin(api: &HttpApi, headers: &HeaderMap) -> Result<(), ApiError> {
+    if !is_allowed_origin(headers, &api.allowed_origins) {
+        return Err(ApiError::forbidden("origin is not allowed"));
+    }
+    Ok(())
+}
+
+/// Joins the topic so we receive its messages.
+async fn join(api: &HttpApi, topic: &Topic) -> Result<(), ApiError> {
+    join_topic(&api.join_tx, topic).await.map_err(|err| {
+        if err.is::<TooManyTopics>() {
+            ApiError::conflict(&err.to_string())
+        } else {
+            ApiError::internal(&err.to_string())
+        }
+    })
+}
+
+fn parse_topic(topic: &str) -> Result<Topic, ApiError> {
+    if topic.is_empty() {
+        return Err(ApiError::bad_request("topic can not be empty"));
+    }
+    Topic::from_str(topic).map_err(|err| ApiError::bad_request(&err.to_string()))
+}
+
+#[derive(Debug, Serialize)]
+struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+}
+
+impl ApiError {
+    fn bad_request(message: &str) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            error: message.to_owned(),
+        }
+    }
+
+    fn forbidden(message: &str) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            error: message.to_owned(),
+        }
+    }
+
+    fn conflict(message: &str) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            error: message.to_owned(),
+        }
+    }
+
+    fn too_many_requests() -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            error: "message was dropped by the publish rate limit".to_owned(),
+        }
+    }
+
+    fn internal(message: &str) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            error: message.to_owned(),
+        }
+    }
+
+    fn unavailable() -> Self {
+        Self {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            error: "node is shutting down".to_owned(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Bytes;
+    use axum::extract::{Path, Query, State};
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+    use p2panda_core::Hash;
+    use p2panda_store::MemoryStore;
+    use tokio::sync::{broadcast, mpsc};
+
+    use crate::node::{JoinRequest, PublishRequest, TooManyTopics};
+    use crate::topic::{AuthorStore, Topic};
+
+    use super::{get_messages, publish_message, HttpApi, MessagesQuery, PublishQuery};
+
+    type Joined = Arc<Mutex<HashSet<Topic>>>;
+
+    /// Answers join requests like the node does when it can join at most `max_topics` topics.
+    fn joiner(max_topics: usize) -> (mpsc::Sender<JoinRequest>, Joined) {
+        let (join_tx, mut join_rx) = mpsc::channel::<JoinRequest>(16);
+        let joined = Joined::default();
+        {
+            let joined = joined.clone();
+            tokio::spawn(async move {
+                while let Some(request) = join_rx.recv().await {
+                    let mut joined = joined.lock().unwrap();
+                    let result = if joined.len() < max_topics {
+                        joined.insert(request.topic);
+                        Ok(())
+                    } else {
+                        Err(TooManyTopics(request.topic).into())
+                    };
+                    let _ = request.reply_tx.send(result);
+                }
+            });
+        }
+        (join_tx, joined)
+    }
+
+    fn api(publish_tx: mpsc::Sender<PublishRequest>) -> HttpApi {
+        HttpApi {
+            publish_tx,
+            join_tx: joiner(1).0,
+            messages_tx: broadcast::channel(16).0,
+            events_tx: broadcast::channel(16).0,
+            operation_store: MemoryStore::new(),
+            author_store: AuthorStore::new(),
+            allowed_origins: Vec::new(),
+        }
+    }
+
+    async fn publish(
+        publish_tx: mpsc::Sender<PublishRequest>,
+        host: &'static str,
+        origin: Option<&'static str>,
+    ) -> Result<String, StatusCode> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static(host));
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, HeaderValue::from_static(origin));
+        }
+
+        let query = PublishQuery {
+            ephemeral: false,
+            ttl: None,
+        };
+        publish_message(
+            State(api(publish_tx)),
+            Path("chat".to_string()),
+            Query(query),
+            headers,
+            Bytes::from_static(b"hello"),
+        )
+        .await
+        .map(|(_, response)| response.0.hash)
+        .map_err(|err| err.status)
+    }
+
+    #[tokio::test]
+    async fn reject_rebound_origins() {
+        let (publish_tx, mut publish_rx) = mpsc::channel(16);
+
+        let result = publish(
+            publish_tx.clone(),
+            "evil.example:41417",
+            Some("http://evil.example:41417"),
+        )
+        .await;
+        assert_eq!(result, Err(StatusCode::FORBIDDEN));
+
+        let result = publish(publish_tx, "evil.example:41417", None).await;
+        assert_eq!(result, Err(StatusCode::FORBIDDEN));
+
+        assert!(publish_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn return_hash_of_published_operation() {
+        let (publish_tx, mut publish_rx) = mpsc::channel::<PublishRequest>(16);
+        tokio::spawn(async move {
+            while let Some(request) = publish_rx.recv().await {
+                let hash = Hash::new(&request.message.payload);
+                let _ = request.reply_tx.unwrap().send(Ok(hash));
+            }
+        });
+
+        let result = publish(publish_tx.clone(), "127.0.0.1:41417", None).await;
+        assert_eq!(result, Ok(Hash::new(b"hello").to_hex()));
+
+        let result = publish(publish_tx, "localhost:41417", Some("http://localhost:8080")).await;
+        assert_eq!(result, Ok(Hash::new(b"hello").to_hex()));
+    }
+
+    #[tokio::test]
+    async fn report_dropped_messages() {
+        let (publish_tx, mut publish_rx) = mpsc::channel::<PublishRequest>(16);
+        tokio::spawn(async move {
+            // The publish rate limit drops the message without replying.
+            while let Some(request) = publish_rx.recv().await {
+                drop(request);
+            }
+        });
+
+        let result = publish(publish_tx, "127.0.0.1:41417", None).await;
+        assert_eq!(result, Err(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    async fn read(
+        join_tx: mpsc::Sender<JoinRequest>,
+        host: &'static str,
+    ) -> Result<usize, StatusCode> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static(host));
+
+        let api = HttpApi {
+            join_tx,
+            ..api(mpsc::channel(16).0)
+        };
+        let query = MessagesQuery {
+            since: None,
+            limit: None,
+        };
+        get_messages(State(api), Path("chat".to_string()), Query(query), headers)
+            .await
+            .map(|messages| messages.0.len())
+            .map_err(|err| err.status)
+    }
+
+    #[tokio::test]
+    async fn join_topic_on_read() {
+        let (join_tx, joined) = joiner(1);
+
+        let result = read(join_tx, "127.0.0.1:41417").await;
+        assert_eq!(result, Ok(0));
+        assert!(joined
+            .lock()
+            .unwrap()
+            .contains(&Topic::from_str("chat").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn reject_topics_over_limit() {
+        let (join_tx, _) = joiner(0);
+
+        let result = read(join_tx, "127.0.0.1:41417").await;
+        assert_eq!(result, Err(StatusCode::CONFLICT));
+    }
+
+    #[tokio::test]
+    async fn reject_rebound_origins_on_read() {
+        let (join_tx, joined) = joiner(1);
+
+        let result = read(join_tx, "evil.example:41417").await;
+        assert_eq!(result, Err(StatusCode::FORBIDDEN));
+        assert!(joined.lock().unwrap().is_empty());
+    }
+}
@@ -1,8 +1,32 @@
+mod bridge;
+mod builder;
+mod events;
+mod fork;
+mod fragment;
+mod http;
+mod message;
+mod mqtt;
 mod node;
 mod operation;
+mod osc;
+mod pipe;
+mod rate_limit;
+mod tasks;
+mod tcp;
 mod topic;
 mod tracing;
+mod udp;
+mod unix;
+mod websocket;
 
+pub use bridge::Bridge;
+pub use builder::{BuildError, ConfigError, NodeBuilder};
+pub use events::Event;
+pub use message::{EnvelopeFormat, Message, OutgoingMessage};
+pub use mqtt::{MqttConfig, MqttMapping};
 pub use node::{Config, Node};
+pub use osc::{AddressPattern, OscConfig, OscRoute};
+pub use pipe::Framing;
+pub use rate_limit::{LimitPolicy, PublishLimit};
 pub use topic::Topic;
 pub use tracing::setup_tracing;
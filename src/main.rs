@@ -1,18 +1,31 @@
-use std::{net::SocketAddr, str::FromStr};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::Parser;
-use meshpit::{setup_tracing, Config, Node, Topic};
+use anyhow::{anyhow, bail, Result};
+use clap::{ArgGroup, Parser, Subcommand};
+use meshpit::{
+    setup_tracing, AddressPattern, Config, EnvelopeFormat, Framing, LimitPolicy, MqttConfig,
+    MqttMapping, Node, OscConfig, OscRoute, PublishLimit, Topic,
+};
 use p2panda_core::{PrivateKey, PublicKey};
 use tracing::info;
 
+/// Seconds the pipe command stays connected after stdin was closed by default.
+const DEFAULT_LINGER: u64 = 5;
+
 #[derive(Debug, Parser)]
 #[command(
     name = "meshpit",
     long_about = None,
-    version
+    version,
+    group(ArgGroup::new("browser_servers").args(["websocket", "http_server"]).multiple(true))
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Define a short text-string which will be automatically hashed and used as a "topic".
     ///
     /// If peers are configured to the same topic, they will find each other automatically, connect
@@ -38,8 +51,173 @@ struct Args {
 
     /// UDP client address and port (default is 49494). meshpit will automatically forward all
     /// received data from other peers to this address.
+    ///
+    /// Use this option multiple times if you want to forward the data to multiple addresses, for
+    /// example to different programs running side by side.
     #[arg(short = 'c', long, value_name = "ADDR:PORT")]
-    udp_client: Option<SocketAddr>,
+    udp_client: Vec<SocketAddr>,
+
+    /// Send received data back to the addresses which sent data to the UDP server, instead of the
+    /// UDP client addresses.
+    ///
+    /// Many programs expect responses on the same port they are sending from, use this mode for
+    /// them.
+    #[arg(short = 'r', long, conflicts_with = "udp_multicast")]
+    reply_to_senders: bool,
+
+    /// Forget addresses which didn't send any data to the UDP server for this number of seconds
+    /// (default is 60). Only used when replying to senders or with UDP subscriptions.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    sender_expiry: Option<u64>,
+
+    /// Allow programs to subscribe themselves to received data by sending a control message to
+    /// the UDP server.
+    ///
+    /// Send "meshpit:subscribe" to receive all data or "meshpit:subscribe <TOPIC>" to join the
+    /// topic and only receive data from it. Use "meshpit:unsubscribe [TOPIC]" to stop receiving
+    /// data again.
+    /// Subscribed programs receive data in addition to the UDP client addresses. Subscriptions
+    /// expire just like senders, send the subscribe message regularly to keep them alive.
+    #[arg(long)]
+    udp_subscriptions: bool,
+
+    /// Send received data to a multicast group or broadcast address and port, instead of the UDP
+    /// client addresses.
+    ///
+    /// Use this for installations with many devices on one network, for example "239.0.0.1:49494"
+    /// for a multicast group, "255.255.255.255:49494" for broadcast or "192.168.1.255:49494" for
+    /// the broadcast address of a local network. Other addresses are rejected.
+    #[arg(short = 'm', long, value_name = "ADDR:PORT")]
+    udp_multicast: Option<SocketAddr>,
+
+    /// Number of hops multicast data is allowed to travel (default is 1, which keeps it in the
+    /// local network).
+    #[arg(long, value_name = "HOPS", requires = "udp_multicast")]
+    multicast_ttl: Option<u8>,
+
+    /// IPv4 address of the network interface used for sending multicast data.
+    ///
+    /// By default the operating system picks one.
+    #[arg(long, value_name = "ADDR", requires = "udp_multicast")]
+    multicast_interface: Option<Ipv4Addr>,
+
+    /// Wrap received data in an envelope with metadata before forwarding it, possible formats
+    /// are "cbor" (compact binary) and "json".
+    ///
+    /// The envelope contains the public key of the author, sequence number and timestamp of the
+    /// message, its hash, the topic id, content type and subject (when given) and the payload.
+    /// Binary values are hex-encoded in JSON.
+    #[arg(short = 'e', long, value_name = "FORMAT")]
+    envelope: Option<EnvelopeFormat>,
+
+    /// Expect every datagram sent to the UDP server (or message sent to the TCP server) to be an
+    /// envelope with options how to publish it, possible formats are "cbor" (compact binary) and
+    /// "json".
+    ///
+    /// The envelope contains the payload ("payload", hex-encoded in JSON) and optionally the name
+    /// of the topic ("topic"), if the message is ephemeral and should not be synced
+    /// ("ephemeral"), a time-to-live in seconds after which it should not be delivered anymore
+    /// ("ttl"), a content type ("content_type") and a subject within the topic ("subject").
+    /// Malformed envelopes are reported back to the sender with an error message. A node joins at
+    /// most 64 topics, messages to further topics are dropped.
+    #[arg(short = 'i', long, value_name = "FORMAT")]
+    input_envelope: Option<EnvelopeFormat>,
+
+    /// Maximum size of datagrams in bytes the UDP server accepts (default is 10000).
+    ///
+    /// Larger datagrams are dropped and reported in the logs (and back to the sender when input
+    /// envelopes are used). Messages which don't fit into a single gossip message are
+    /// automatically split.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..=65507))]
+    max_message_size: Option<u32>,
+
+    /// Expect Open Sound Control (OSC) packets on the UDP server and from other peers.
+    ///
+    /// Datagrams which are not valid OSC messages or bundles are dropped before they get
+    /// published.
+    #[arg(long, conflicts_with_all = ["envelope", "input_envelope"])]
+    osc: bool,
+
+    /// Only publish OSC messages with an address matching this pattern, for example "/scene/*".
+    ///
+    /// Use this option multiple times to publish messages matching any of the patterns. Bundles
+    /// are published with all matching messages.
+    #[arg(long, value_name = "PATTERN", requires = "osc")]
+    osc_publish: Vec<AddressPattern>,
+
+    /// Send OSC messages from other peers with an address matching the pattern to another
+    /// address and port instead of the UDP clients, for example "/sensor/*=127.0.0.1:49495".
+    ///
+    /// Use this option multiple times for multiple routes, the first matching route is used.
+    #[arg(long, value_name = "PATTERN=ADDR:PORT", requires = "osc")]
+    osc_route: Vec<OscRoute>,
+
+    /// TCP server address and port. Connect to it if you want to send and receive larger
+    /// messages reliably.
+    ///
+    /// Every message is prefixed with its length in bytes as an unsigned 32-bit big-endian
+    /// integer, in both directions. Envelopes are used just like with the UDP server.
+    #[arg(long, value_name = "ADDR:PORT")]
+    tcp_server: Option<SocketAddr>,
+
+    /// WebSocket server address and port. Connect to it from the browser if you want to use
+    /// meshpit in web sketches.
+    ///
+    /// Binary messages are handled just like datagrams sent to the UDP server, text messages are
+    /// always expected to be JSON envelopes. Received data is sent as binary messages, or as text
+    /// messages when JSON envelopes are used.
+    #[arg(long, value_name = "ADDR:PORT")]
+    websocket: Option<SocketAddr>,
+
+    /// HTTP server address and port for scripts and web dashboards.
+    ///
+    /// Send the payload with "POST /topics/<TOPIC>/messages" (optionally with "ephemeral" and
+    /// "ttl" query parameters and a content type header) and receive the hash of the published
+    /// operation as soon as the publish rate limit allows it, read the latest stored messages with
+    /// "GET /topics/<TOPIC>/messages?since=<TIMESTAMP>&limit=<COUNT>" (at most 1000) and receive
+    /// new messages as Server-Sent Events with "GET /topics/<TOPIC>/events". Reading a topic joins
+    /// it. Messages are returned as JSON envelopes.
+    #[arg(long, value_name = "ADDR:PORT")]
+    http_server: Option<SocketAddr>,
+
+    /// Allow browsers to connect to the WebSocket server and use the HTTP API from pages of this
+    /// origin, for example "https://example.org" ("*" allows all origins).
+    ///
+    /// By default only pages served from the same host are allowed and the server needs to be
+    /// addressed as "localhost" or by its IP address, this protects against DNS rebinding. Use
+    /// this option multiple times for multiple origins.
+    #[arg(long, value_name = "ORIGIN", requires = "browser_servers")]
+    allowed_origin: Vec<String>,
+
+    /// Host name or address and port of a MQTT broker to connect to, for example
+    /// "localhost:1883".
+    ///
+    /// The broker needs to support MQTT 5.
+    #[arg(long, value_name = "HOST:PORT", requires = "mqtt_map", value_parser = parse_host_port)]
+    mqtt_broker: Option<(String, u16)>,
+
+    /// Publish all MQTT messages matching the topic filter to a meshpit topic and republish all
+    /// data received on that topic to the MQTT broker, for example "sensors/#=sensors".
+    ///
+    /// The original MQTT topic is sent along with the data, so other peers can republish it
+    /// under the same name. Use this option multiple times for multiple mappings.
+    #[arg(long, value_name = "FILTER=TOPIC", requires = "mqtt_broker")]
+    mqtt_map: Vec<MqttMapping>,
+
+    /// Path of an Unix datagram socket for programs running on the same computer. It works just
+    /// like the UDP server.
+    ///
+    /// Only your user can write to the socket, use this instead of the UDP server if you don't
+    /// want other users on the same computer to send data.
+    #[arg(long, value_name = "PATH")]
+    unix_server: Option<PathBuf>,
+
+    /// Path of an Unix datagram socket meshpit forwards all received data to, just like the UDP
+    /// client.
+    ///
+    /// Use this option multiple times if you want to forward the data to multiple sockets.
+    #[arg(long, value_name = "PATH", requires = "unix_server")]
+    unix_client: Vec<PathBuf>,
 
     /// Disable sync for this node.
     ///
@@ -48,6 +226,79 @@ struct Args {
     #[arg(short = 'n', long)]
     no_sync: bool,
 
+    /// Maximum number of operations per second we accept from each author.
+    ///
+    /// Operations from peers exceeding this limit are not forwarded to the UDP client. Use this to
+    /// protect your installation from peers flooding the network. The operations are still stored
+    /// and synced with other peers. Large payloads which were split into multiple operations count
+    /// as one.
+    #[arg(long, value_name = "OPS_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    max_author_ops: Option<u32>,
+
+    /// Maximum number of payload bytes per second we accept from each author.
+    ///
+    /// Operations from peers exceeding this limit are not forwarded to the UDP client. Larger
+    /// operations are accepted after the author didn't send anything for a second.
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u32).range(1..))]
+    max_author_bytes: Option<u32>,
+
+    /// Maximum number of messages per second published to the topic.
+    ///
+    /// Every message sent to meshpit becomes a signed operation, use this limit if your
+    /// application is sending data at a high rate. Prefix the value with the name of another
+    /// topic to limit that one instead, for example "sensors=10". Use this option multiple times
+    /// for multiple topics.
+    #[arg(long, value_name = "[TOPIC=]MSGS_PER_SEC")]
+    publish_rate: Vec<TopicOption<u32>>,
+
+    /// Number of messages published at once before the rate limit applies (default is the
+    /// publish rate).
+    ///
+    /// Prefix the value with the name of the topic, just like the publish rate.
+    #[arg(long, value_name = "[TOPIC=]COUNT", requires = "publish_rate")]
+    publish_burst: Vec<TopicOption<u32>>,
+
+    /// What to do with messages exceeding the publish rate (default is "drop").
+    ///
+    /// Possible policies are: "drop" (discard them), "queue" (publish them later in order) and
+    /// "coalesce-latest" (only publish the latest one later). Prefix the value with the name of
+    /// the topic, just like the publish rate.
+    #[arg(long, value_name = "[TOPIC=]POLICY", requires = "publish_rate")]
+    publish_policy: Vec<TopicOption<LimitPolicy>>,
+
+    /// Reject operations from peers which claim to be created more than this number of seconds
+    /// in the future.
+    ///
+    /// Use this to protect your installation from peers with broken clocks.
+    #[arg(long, value_name = "SECONDS")]
+    max_clock_drift: Option<u64>,
+
+    /// Reject operations arriving via the broadcast gossip overlay which were created more than
+    /// this number of seconds ago.
+    ///
+    /// Use this to protect your installation from replayed, ancient data. Past data can still
+    /// arrive via sync.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "no_sync")]
+    max_gossip_age: Option<u64>,
+
+    /// Block all further data from peers who forked their log.
+    ///
+    /// Every peer writes into an append-only log, two different messages at the same position of
+    /// that log indicate a buggy or malicious peer. Forks are always rejected and reported, with
+    /// this flag we also stop accepting any other data from that peer.
+    #[arg(long)]
+    block_forks: bool,
+
+    /// Require a proof-of-work stamp with this difficulty (number of leading zero bits, between 1
+    /// and 20) on every message in the topic.
+    ///
+    /// This makes it expensive to spam open topics. All peers in a topic need to use the same
+    /// difficulty, messages not meeting it are rejected. Every increment doubles the work needed
+    /// to publish a message. Prefix the value with the name of another topic to require it there
+    /// instead, for example "chat=16". Use this option multiple times for multiple topics.
+    #[arg(long, value_name = "[TOPIC=]BITS")]
+    pow_difficulty: Vec<TopicOption<u8>>,
+
     /// Set log verbosity. Use this for learning more about how your node behaves or for debugging.
     ///
     /// Possible log levels are: ERROR, WARN, INFO, DEBUG, TRACE. They are scoped to "meshpit" by
@@ -59,25 +310,193 @@ struct Args {
     log_level: Option<String>,
 }
 
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Publish data read from stdin and write received data to stdout.
+    ///
+    /// Use this to combine meshpit with other programs in shell pipelines, all other options
+    /// need to be set before the command.
+    Pipe {
+        /// How messages are separated from each other (default is "lines").
+        ///
+        /// Possible values are: "lines" (every line is one message) and "length-prefixed" (every
+        /// message is prefixed with its length in bytes as an unsigned 32-bit big-endian integer,
+        /// use this for binary data).
+        #[arg(short = 'f', long, value_name = "FRAMING")]
+        framing: Option<Framing>,
+
+        /// Keep running after stdin was closed and write received data to stdout until the node
+        /// is stopped.
+        ///
+        /// By default meshpit publishes all messages read from stdin and exits when it was closed.
+        #[arg(short = 'k', long)]
+        keep_running: bool,
+
+        /// Stay connected for this number of seconds after stdin was closed (default is 5).
+        ///
+        /// Before exiting meshpit waits until it found other peers for every topic it published
+        /// to, the time afterwards gives them the chance to sync messages they missed.
+        #[arg(long, value_name = "SECONDS", conflicts_with = "keep_running")]
+        linger: Option<u64>,
+    },
+}
+
+/// Value of an option which can be given per topic, the node's topic is used when none is given.
+#[derive(Clone, Debug)]
+struct TopicOption<T> {
+    topic: Option<String>,
+    value: T,
+}
+
+impl<T> TopicOption<T> {
+    fn topic(&self, default: &Topic) -> Result<Topic> {
+        match &self.topic {
+            Some(topic) => Topic::from_str(topic),
+            None => Ok(default.clone()),
+        }
+    }
+}
+
+impl<T> FromStr for TopicOption<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (topic, value) = match value.rsplit_once('=') {
+            Some(("", _)) => bail!("topic can not be empty"),
+            Some((topic, value)) => (Some(topic.to_owned()), value),
+            None => (None, value),
+        };
+
+        let value = value.parse().map_err(|err| anyhow!("{err}"))?;
+
+        Ok(Self { topic, value })
+    }
+}
+
+/// Parses a "host:port" string, IPv6 addresses need to be enclosed in brackets.
+fn parse_host_port(value: &str) -> Result<(String, u16)> {
+    let Some((host, port)) = value.rsplit_once(':') else {
+        bail!("address needs to be in the form of <HOST>:<PORT>");
+    };
+
+    let host = match host.strip_prefix('[') {
+        Some(host) => host
+            .strip_suffix(']')
+            .ok_or_else(|| anyhow!("missing closing bracket in \"{value}\""))?,
+        None if host.contains(':') => bail!("IPv6 addresses need to be enclosed in brackets"),
+        None => host,
+    };
+    if host.is_empty() {
+        bail!("host can not be empty");
+    }
+
+    Ok((host.to_owned(), port.parse()?))
+}
+
 impl TryFrom<Args> for Config {
     type Error = anyhow::Error;
 
     fn try_from(args: Args) -> std::result::Result<Self, Self::Error> {
-        let mut config = Config::default();
-
-        config.bootstrap = args.bootstrap;
-        config.no_sync = args.no_sync;
+        let mut config = Config {
+            bootstrap: args.bootstrap,
+            no_sync: args.no_sync,
+            max_author_operations: args.max_author_ops,
+            max_author_bytes: args.max_author_bytes,
+            max_clock_drift: args.max_clock_drift.map(Duration::from_secs),
+            max_gossip_age: args.max_gossip_age.map(Duration::from_secs),
+            block_forks: args.block_forks,
+            udp_reply_to_senders: args.reply_to_senders,
+            udp_subscriptions: args.udp_subscriptions,
+            udp_multicast_addr: args.udp_multicast,
+            udp_multicast_interface: args.multicast_interface,
+            udp_envelope: args.envelope,
+            udp_input: args.input_envelope,
+            tcp_server_addr: args.tcp_server,
+            websocket_addr: args.websocket,
+            allowed_origins: args.allowed_origin,
+            http_addr: args.http_server,
+            unix_server_path: args.unix_server,
+            unix_client_paths: args.unix_client,
+            ..Default::default()
+        };
 
         if let Some(topic) = &args.topic {
             config.topic = Topic::from_str(topic)?;
         }
 
         if let Some(addr) = &args.udp_server {
-            config.udp_server_addr = *addr;
+            config.udp_server_addr = Some(*addr);
+        }
+
+        if !args.udp_client.is_empty() {
+            config.udp_client_addrs = args.udp_client;
         }
 
-        if let Some(addr) = &args.udp_client {
-            config.udp_client_addr = *addr;
+        if let Some(max_message_size) = args.max_message_size {
+            config.max_message_size = max_message_size as usize;
+        }
+
+        if let Some((broker_host, broker_port)) = args.mqtt_broker {
+            config.mqtt = Some(MqttConfig {
+                broker_host,
+                broker_port,
+                mappings: args.mqtt_map,
+            });
+        }
+
+        if args.osc {
+            config.osc = Some(OscConfig {
+                publish: args.osc_publish,
+                routes: args.osc_route,
+            });
+        }
+
+        if let Some(ttl) = args.multicast_ttl {
+            config.udp_multicast_ttl = ttl;
+        }
+
+        if let Some(expiry) = args.sender_expiry {
+            config.udp_sender_expiry = Duration::from_secs(expiry);
+            config.udp_subscription_expiry = Duration::from_secs(expiry);
+        }
+
+        if let Some(Command::Pipe { framing, .. }) = args.command {
+            config.pipe = Some(framing.unwrap_or_default());
+        }
+
+        for option in &args.pow_difficulty {
+            config
+                .pow_difficulties
+                .insert(option.topic(&config.topic)?, option.value);
+        }
+
+        for option in &args.publish_rate {
+            config.publish_limits.insert(
+                option.topic(&config.topic)?,
+                PublishLimit {
+                    rate: option.value,
+                    burst: option.value,
+                    policy: LimitPolicy::default(),
+                },
+            );
+        }
+
+        for option in &args.publish_burst {
+            let Some(limit) = config.publish_limits.get_mut(&option.topic(&config.topic)?) else {
+                bail!("publish burst of a topic can only be used together with its publish rate");
+            };
+            limit.burst = option.value;
+        }
+
+        for option in &args.publish_policy {
+            let Some(limit) = config.publish_limits.get_mut(&option.topic(&config.topic)?) else {
+                bail!("publish policy of a topic can only be used together with its publish rate");
+            };
+            limit.policy = option.value;
         }
 
         Ok(config)
@@ -90,6 +509,15 @@ async fn main() -> Result<()> {
 
     setup_tracing(args.log_level.clone().unwrap_or_default());
 
+    let linger = match args.command {
+        Some(Command::Pipe {
+            keep_running: false,
+            linger,
+            ..
+        }) => Some(Duration::from_secs(linger.unwrap_or(DEFAULT_LINGER))),
+        _ => None,
+    };
+
     let config: Config = args.try_into()?;
     let private_key = PrivateKey::new();
 
@@ -106,10 +534,51 @@ async fn main() -> Result<()> {
     for addr in node.addrs().await? {
         info!("- {}", addr);
     }
-    info!("udp server: {}", node.udp_server_addr().await?);
-    info!("udp client: {}", node.udp_client_addr());
+    if let Some(addr) = node.udp_server_addr() {
+        info!("udp server: {}", addr);
+        if node.udp_reply_to_senders() {
+            info!("udp client: reply to senders");
+        } else if let Some(addr) = node.udp_multicast_addr() {
+            info!("udp multicast: {}", addr);
+        } else {
+            info!("udp client:");
+            for addr in node.udp_client_addrs() {
+                info!("- {}", addr);
+            }
+        }
+    }
+    if let Some(addr) = node.tcp_server_addr() {
+        info!("tcp server: {}", addr);
+    }
+    if let Some(addr) = node.websocket_addr() {
+        info!("websocket server: ws://{}", addr);
+    }
+    if let Some(addr) = node.http_addr() {
+        info!("http server: http://{}", addr);
+    }
+    if let Some(path) = node.unix_server_path() {
+        info!("unix server: {}", path.display());
+        if !node.unix_client_paths().is_empty() {
+            info!("unix client:");
+            for path in node.unix_client_paths() {
+                info!("- {}", path.display());
+            }
+        }
+    }
+
+    let exit_on_eof = async {
+        let Some(linger) = linger else {
+            return std::future::pending().await;
+        };
+        let topics = node.stdin_closed().await;
+        info!("stdin closed, wait for other peers to receive published messages");
+        node.linger(&topics, linger).await;
+    };
 
-    tokio::signal::ctrl_c().await?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = exit_on_eof => info!("shut down node"),
+    }
 
     node.shutdown().await?;
 
@@ -0,0 +1,506 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use p2panda_core::cbor::{decode_cbor, encode_cbor};
+use p2panda_core::{Body, Hash, Header, PublicKey};
+use p2panda_net::TopicId;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::node::MAX_PUBLISH_SIZE;
+use crate::operation::Extensions;
+use crate::topic::Topic;
+
+/// Payload we've received from another peer, together with information about who sent it and
+/// when.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub public_key: PublicKey,
+    pub seq_num: u64,
+    pub timestamp: u64,
+    pub hash: Hash,
+    pub topic: Topic,
+    pub content_type: Option<String>,
+    pub subject: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// Creates a message from an operation we've received or stored.
+    pub fn from_operation(header: &Header<Extensions>, body: &Body) -> Self {
+        let extensions = header
+            .extensions
+            .as_ref()
+            .expect("extensions exist in header");
+
+        Self {
+            public_key: header.public_key,
+            seq_num: header.seq_num,
+            timestamp: header.timestamp,
+            hash: header.hash(),
+            topic: extensions.topic(),
+            content_type: extensions.content_type().map(str::to_owned),
+            subject: extensions.subject().map(str::to_owned),
+            payload: body.to_bytes(),
+        }
+    }
+
+    /// Encodes the message for the local application, either as the plain payload or wrapped in
+    /// an envelope with all metadata.
+    pub fn to_bytes(&self, envelope: Option<EnvelopeFormat>) -> Result<Vec<u8>> {
+        let Some(format) = envelope else {
+            return Ok(self.payload.clone());
+        };
+
+        let bytes = match format {
+            EnvelopeFormat::Cbor => encode_cbor(self)?,
+            EnvelopeFormat::Json => serde_json::to_vec(self)?,
+        };
+
+        Ok(bytes)
+    }
+}
+
+/// Messages are serialized as envelopes with all metadata.
+impl Serialize for Message {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Envelope {
+            public_key: self.public_key,
+            seq_num: self.seq_num,
+            timestamp: self.timestamp,
+            hash: self.hash,
+            topic: Hash::from(self.topic.id()),
+            content_type: self.content_type.as_deref(),
+            subject: self.subject.as_deref(),
+            payload: &self.payload,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Payload the local application wants to publish, together with options how to publish it.
+#[derive(Clone, Debug, Default)]
+pub struct OutgoingMessage {
+    /// Topic to publish to, the node's topic is used when none is given.
+    pub topic: Option<Topic>,
+
+    /// Ephemeral messages are only delivered to peers who are online and never synced.
+    pub ephemeral: bool,
+
+    /// Number of seconds after which the message should not be delivered anymore.
+    pub ttl: Option<u64>,
+
+    /// Content type of the payload, for example a MIME type.
+    pub content_type: Option<String>,
+
+    /// Address of the payload within the topic, for example the original MQTT topic.
+    pub subject: Option<String>,
+
+    pub payload: Vec<u8>,
+}
+
+impl OutgoingMessage {
+    pub fn new(payload: Vec<u8>) -> Self {
+        Self {
+            payload,
+            ..Default::default()
+        }
+    }
+
+    /// Decodes and validates an envelope sent by the local application.
+    ///
+    /// Envelopes contain the payload and optionally the name of the topic, if the message is
+    /// ephemeral, a time-to-live in seconds and a content type. Binary values are hex-encoded in
+    /// JSON.
+    pub fn from_envelope(bytes: &[u8], format: EnvelopeFormat) -> Result<Self> {
+        let envelope: InputEnvelope = match format {
+            EnvelopeFormat::Cbor => decode_cbor(bytes)?,
+            EnvelopeFormat::Json => serde_json::from_slice(bytes)?,
+        };
+
+        let topic = match envelope.topic {
+            Some(topic) if topic.is_empty() => bail!("topic can not be empty"),
+            Some(topic) => Some(Topic::from_str(&topic)?),
+            None => None,
+        };
+
+        let message = Self {
+            topic,
+            ephemeral: envelope.ephemeral,
+            ttl: envelope.ttl,
+            content_type: envelope.content_type,
+            subject: envelope.subject,
+            payload: envelope.payload,
+        };
+        message.validate()?;
+
+        Ok(message)
+    }
+
+    /// Checks if the publishing options are valid.
+    pub fn validate(&self) -> Result<()> {
+        if self.payload.len() > MAX_PUBLISH_SIZE {
+            bail!(
+                "payload of {} bytes exceeds max. size of {MAX_PUBLISH_SIZE} bytes",
+                self.payload.len()
+            );
+        }
+
+        if self.ttl == Some(0) {
+            bail!("ttl needs to be larger than zero");
+        }
+
+        if let Some(content_type) = &self.content_type {
+            if content_type.is_empty() || content_type.len() > MAX_CONTENT_TYPE_LEN {
+                bail!("content type needs to be between 1 and {MAX_CONTENT_TYPE_LEN} characters");
+            }
+        }
+
+        if let Some(subject) = &self.subject {
+            if subject.is_empty() || subject.len() > MAX_SUBJECT_LEN {
+                bail!("subject needs to be between 1 and {MAX_SUBJECT_LEN} characters");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Encodes an error message for the local application in the given envelope format.
+pub fn encode_error(message: &str, format: EnvelopeFormat) -> Result<Vec<u8>> {
+    let error = ErrorEnvelope { error: message };
+    let bytes = match format {
+        EnvelopeFormat::Cbor => encode_cbor(&error)?,
+        EnvelopeFormat::Json => serde_json::to_vec(&error)?,
+    };
+    Ok(bytes)
+}
+
+/// Formats of the envelope we can wrap around forwarded payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvelopeFormat {
+    /// Compact binary encoding.
+    Cbor,
+
+    /// Human readable encoding, all binary values are hex-encoded.
+    Json,
+}
+
+impl fmt::Display for EnvelopeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            EnvelopeFormat::Cbor => "cbor",
+            EnvelopeFormat::Json => "json",
+        };
+        write!(f, "{value}")
+    }
+}
+
+impl FromStr for EnvelopeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => bail!("unknown envelope format \"{value}\", possible values are: cbor, json"),
+        }
+    }
+}
+
+const MAX_CONTENT_TYPE_LEN: usize = 128;
+
+const MAX_SUBJECT_LEN: usize = 256;
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    public_key: PublicKey,
+    seq_num: u64,
+    timestamp: u64,
+    hash: Hash,
+    topic: Hash,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<&'a str>,
+    #[serde(serialize_with = "serialize_bytes")]
+    payload: &'a [u8],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputEnvelope {
+    topic: Option<String>,
+    #[serde(default)]
+    ephemeral: bool,
+    ttl: Option<u64>,
+    content_type: Option<String>,
+    subject: Option<String>,
+    #[serde(deserialize_with = "deserialize_bytes")]
+    payload: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: &'a str,
+}
+
+/// Serializes bytes into a hex string when using a human readable encoding (JSON), otherwise it
+/// serializes the bytes directly (CBOR).
+fn serialize_bytes<S>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&hex::encode(value))
+    } else {
+        serializer.serialize_bytes(value)
+    }
+}
+
+/// Deserializes bytes from a hex string when using a human readable encoding (JSON), otherwise it
+/// deserializes the bytes directly (CBOR).
+fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("hex string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            hex::decode(value).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+            Ok(value.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(value)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    // The CBOR decoder rejects byte strings larger than its scratch buffer unless we ask for an
+    // owned buffer.
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(BytesVisitor)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use p2panda_core::cbor::{decode_cbor, encode_cbor};
+    use p2panda_core::{Hash, PrivateKey, PublicKey};
+    use p2panda_net::TopicId;
+    use serde::{Deserialize, Serialize};
+
+    use crate::node::MAX_PUBLISH_SIZE;
+    use crate::topic::Topic;
+
+    use super::{
+        deserialize_bytes, serialize_bytes, EnvelopeFormat, Message, OutgoingMessage,
+        MAX_CONTENT_TYPE_LEN, MAX_SUBJECT_LEN,
+    };
+
+    fn message() -> Message {
+        Message {
+            public_key: PrivateKey::new().public_key(),
+            seq_num: 3,
+            timestamp: 1737801000,
+            hash: Hash::new(b"operation"),
+            topic: Topic::from_str("chat").unwrap(),
+            content_type: Some("text/plain".to_owned()),
+            subject: None,
+            payload: b"hello".to_vec(),
+        }
+    }
+
+    /// Envelope as the local application reads it.
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct DecodedEnvelope {
+        public_key: PublicKey,
+        seq_num: u64,
+        timestamp: u64,
+        hash: Hash,
+        topic: Hash,
+        content_type: Option<String>,
+        subject: Option<String>,
+        #[serde(deserialize_with = "deserialize_bytes")]
+        payload: Vec<u8>,
+    }
+
+    fn assert_envelope(envelope: DecodedEnvelope, message: &Message) {
+        assert_eq!(envelope.public_key, message.public_key);
+        assert_eq!(envelope.seq_num, message.seq_num);
+        assert_eq!(envelope.timestamp, message.timestamp);
+        assert_eq!(envelope.hash, message.hash);
+        assert_eq!(envelope.topic, Hash::from(message.topic.id()));
+        assert_eq!(envelope.content_type, message.content_type);
+        assert_eq!(envelope.subject, message.subject);
+        assert_eq!(envelope.payload, message.payload);
+    }
+
+    #[test]
+    fn plain_payload() {
+        let message = message();
+        assert_eq!(message.to_bytes(None).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn cbor_envelope() {
+        let message = message();
+        let bytes = message.to_bytes(Some(EnvelopeFormat::Cbor)).unwrap();
+        assert_envelope(decode_cbor(&bytes[..]).unwrap(), &message);
+    }
+
+    #[test]
+    fn json_envelope() {
+        let message = message();
+        let bytes = message.to_bytes(Some(EnvelopeFormat::Json)).unwrap();
+        assert_envelope(serde_json::from_slice(&bytes).unwrap(), &message);
+
+        // Binary values are hex-encoded, empty options are left out.
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["payload"], "68656c6c6f");
+        assert_eq!(value["hash"], message.hash.to_hex());
+        assert_eq!(value["public_key"], message.public_key.to_hex());
+        assert!(value.get("subject").is_none());
+    }
+
+    /// Envelope as the local application writes it.
+    #[derive(Default, Serialize)]
+    struct InputEnvelope<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        topic: Option<&'a str>,
+        ephemeral: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_type: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        subject: Option<&'a str>,
+        #[serde(serialize_with = "serialize_bytes")]
+        payload: &'a [u8],
+    }
+
+    fn decode(envelope: &InputEnvelope, format: EnvelopeFormat) -> anyhow::Result<OutgoingMessage> {
+        let bytes = match format {
+            EnvelopeFormat::Cbor => encode_cbor(envelope).unwrap(),
+            EnvelopeFormat::Json => serde_json::to_vec(envelope).unwrap(),
+        };
+        OutgoingMessage::from_envelope(&bytes, format)
+    }
+
+    #[test]
+    fn decode_input_envelopes() {
+        let envelope = InputEnvelope {
+            topic: Some("chat"),
+            ephemeral: true,
+            ttl: Some(60),
+            content_type: Some("text/plain"),
+            subject: Some("sensors/1"),
+            payload: b"hello",
+        };
+
+        for format in [EnvelopeFormat::Cbor, EnvelopeFormat::Json] {
+            let message = decode(&envelope, format).unwrap();
+            assert_eq!(message.topic, Some(Topic::from_str("chat").unwrap()));
+            assert!(message.ephemeral);
+            assert_eq!(message.ttl, Some(60));
+            assert_eq!(message.content_type.as_deref(), Some("text/plain"));
+            assert_eq!(message.subject.as_deref(), Some("sensors/1"));
+            assert_eq!(message.payload, b"hello");
+        }
+
+        // Only the payload is required, it is hex-encoded in JSON.
+        let message =
+            OutgoingMessage::from_envelope(br#"{"payload":"68656c6c6f"}"#, EnvelopeFormat::Json)
+                .unwrap();
+        assert_eq!(message.topic, None);
+        assert!(!message.ephemeral);
+        assert_eq!(message.ttl, None);
+        assert_eq!(message.payload, b"hello");
+    }
+
+    #[test]
+    fn reject_invalid_input_envelopes() {
+        for json in [
+            r#"{"payload":"68656c6c6f","unknown":true}"#,
+            r#"{"payload":"hello"}"#,
+            r#"{"topic":"chat"}"#,
+            r#"{"topic":"","payload":""}"#,
+            r#"{"ttl":0,"payload":""}"#,
+            r#"{"ttl":-1,"payload":""}"#,
+            r#"{"content_type":"","payload":""}"#,
+            r#"{"subject":"","payload":""}"#,
+        ] {
+            assert!(
+                OutgoingMessage::from_envelope(json.as_bytes(), EnvelopeFormat::Json).is_err(),
+                "{json}"
+            );
+        }
+
+        let content_type = "a".repeat(MAX_CONTENT_TYPE_LEN + 1);
+        let subject = "a".repeat(MAX_SUBJECT_LEN + 1);
+        let payload = vec![0; MAX_PUBLISH_SIZE + 1];
+        for envelope in [
+            InputEnvelope {
+                ttl: Some(0),
+                ..Default::default()
+            },
+            InputEnvelope {
+                content_type: Some(&content_type),
+                ..Default::default()
+            },
+            InputEnvelope {
+                subject: Some(&subject),
+                ..Default::default()
+            },
+            InputEnvelope {
+                payload: &payload,
+                ..Default::default()
+            },
+        ] {
+            for format in [EnvelopeFormat::Cbor, EnvelopeFormat::Json] {
+                assert!(decode(&envelope, format).is_err());
+            }
+        }
+
+        // The largest values are still accepted.
+        let content_type = "a".repeat(MAX_CONTENT_TYPE_LEN);
+        let subject = "a".repeat(MAX_SUBJECT_LEN);
+        let payload = vec![0; MAX_PUBLISH_SIZE];
+        let envelope = InputEnvelope {
+            content_type: Some(&content_type),
+            subject: Some(&subject),
+            payload: &payload,
+            ..Default::default()
+        };
+        for format in [EnvelopeFormat::Cbor, EnvelopeFormat::Json] {
+            assert!(decode(&envelope, format).is_ok());
+        }
+    }
+}
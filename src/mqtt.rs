@@ -0,0 +1,382 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use p2panda_core::Hash;
+use rumqttc::v5::mqttbytes::v5::{Packet, Publish, PublishProperties};
+use rumqttc::v5::mqttbytes::{has_wildcards, matches, valid_filter, QoS};
+use rumqttc::v5::{AsyncClient, Event, EventLoop, MqttOptions};
+use rumqttc::Outgoing;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+use tracing::{debug, info, warn};
+
+use crate::bridge::Bridge;
+use crate::events::{report_bridge_error, Event as NodeEvent};
+use crate::message::{Message, OutgoingMessage};
+use crate::tasks::TaskGroup;
+use crate::topic::Topic;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// How long we keep the connection open on shutdown to publish pending messages.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Messages we've republished to the broker are tagged with this MQTT user property. They come
+// back to us (or to other meshpit nodes connected to the same broker) when they match a filter,
+// we never publish them again as they would travel in a loop otherwise.
+const REPUBLISHED_PROPERTY: &str = "meshpit-hash";
+
+/// Maps all MQTT topics matching the filter to a meshpit topic and back.
+#[derive(Clone, Debug)]
+pub struct MqttMapping {
+    pub filter: String,
+    pub topic: Topic,
+}
+
+impl FromStr for MqttMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((filter, topic)) = value.rsplit_once('=') else {
+            bail!("mapping needs to be in the form of <FILTER>=<TOPIC>");
+        };
+
+        if !valid_filter(filter) {
+            bail!("invalid mqtt topic filter \"{filter}\"");
+        }
+
+        if topic.is_empty() {
+            bail!("topic can not be empty");
+        }
+
+        Ok(Self {
+            filter: filter.to_owned(),
+            topic: Topic::from_str(topic)?,
+        })
+    }
+}
+
+impl MqttMapping {
+    /// Returns the MQTT topic a message with the given subject is republished to.
+    ///
+    /// This is the subject if it matches the filter, otherwise the filter itself if it doesn't
+    /// contain any wildcards.
+    fn mqtt_topic(&self, subject: Option<&str>) -> Option<String> {
+        match subject {
+            Some(subject) if matches(subject, &self.filter) => Some(subject.to_owned()),
+            _ if !has_wildcards(&self.filter) => Some(self.filter.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    /// Host name or IP address of the MQTT broker.
+    pub broker_host: String,
+
+    pub broker_port: u16,
+
+    pub mappings: Vec<MqttMapping>,
+}
+
+/// Connects to a MQTT broker, publishes all MQTT messages matching the filters of the mappings to
+/// their meshpit topics and republishes all messages received on these topics to the broker.
+///
+/// The original MQTT topic is published as the subject of the message. Messages without a subject
+/// can only be republished when the filter doesn't contain any wildcards. MQTT 5 is used, so we
+/// can recognize messages which were republished by meshpit.
+#[derive(Debug)]
+pub struct MqttBridge {
+    client: AsyncClient,
+    messages_rx: mpsc::Receiver<OutgoingMessage>,
+    mappings: Vec<MqttMapping>,
+}
+
+impl MqttBridge {
+    /// Connects to the broker in a background task of the given group, which reconnects until
+    /// the group shuts down.
+    pub fn connect(
+        config: &MqttConfig,
+        client_id: &str,
+        events_tx: broadcast::Sender<NodeEvent>,
+        tasks: &TaskGroup,
+    ) -> Self {
+        let options = MqttOptions::new(client_id, &config.broker_host, config.broker_port);
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let (messages_tx, messages_rx) = mpsc::channel(64);
+
+        {
+            let client = client.clone();
+            let mappings = config.mappings.clone();
+            tasks.spawn_graceful("mqtt", |shutdown| async move {
+                tokio::select! {
+                    _ = poll(&mut eventloop, client, mappings, messages_tx, events_tx) => {
+                        return Ok(());
+                    }
+                    _ = shutdown.cancelled() => (),
+                }
+
+                // Keep publishing the pending messages of the bridge until it disconnects.
+                let _ = time::timeout(DISCONNECT_TIMEOUT, async {
+                    loop {
+                        match eventloop.poll().await {
+                            Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                            Ok(_) => (),
+                        }
+                    }
+                })
+                .await;
+
+                Ok(())
+            });
+        }
+
+        Self {
+            client,
+            messages_rx,
+            mappings: config.mappings.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Bridge for MqttBridge {
+    fn name(&self) -> &'static str {
+        "mqtt"
+    }
+
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>> {
+        Ok(self.messages_rx.recv().await)
+    }
+
+    async fn deliver(&mut self, message: &Message) -> Result<()> {
+        // Only use the first matching mapping.
+        let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.topic == message.topic)
+        else {
+            return Ok(());
+        };
+
+        let Some(mqtt_topic) = mapping.mqtt_topic(message.subject.as_deref()) else {
+            debug!(hash = %message.hash, "no mqtt topic for message, don't republish");
+            return Ok(());
+        };
+
+        // The request queue of the client only empties while the event loop forwards MQTT messages
+        // to us. Waiting for it here while the event loop waits for us to take its messages would
+        // block both, so we drop the message when the queue is full.
+        self.client
+            .try_publish_with_properties(
+                mqtt_topic,
+                QoS::AtLeastOnce,
+                false,
+                message.payload.clone(),
+                republished_properties(&message.hash),
+            )
+            .context("could not publish mqtt message, drop it")?;
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Fails when the connection was lost already, nothing is pending then.
+        let _ = self.client.disconnect().await;
+        Ok(())
+    }
+}
+
+/// Handles the connection to the broker and forwards all MQTT messages matching the mappings.
+async fn poll(
+    eventloop: &mut EventLoop,
+    client: AsyncClient,
+    mappings: Vec<MqttMapping>,
+    messages_tx: mpsc::Sender<OutgoingMessage>,
+    events_tx: broadcast::Sender<NodeEvent>,
+) {
+    loop {
+        let publish = match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker");
+                // Subscriptions need to be renewed after every reconnect.
+                for mapping in &mappings {
+                    if let Err(err) = client.try_subscribe(&mapping.filter, QoS::AtLeastOnce) {
+                        warn!("could not subscribe to mqtt topic filter: {err}");
+                        report_bridge_error(&events_tx, "mqtt", &err);
+                    }
+                }
+                continue;
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => publish,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("mqtt connection error: {err}");
+                report_bridge_error(&events_tx, "mqtt", &err);
+                time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+
+        let Some(message) = incoming_message(&mappings, &publish) else {
+            continue;
+        };
+
+        if messages_tx.send(message).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Tags messages we republish to the broker.
+fn republished_properties(hash: &Hash) -> PublishProperties {
+    PublishProperties {
+        user_properties: vec![(REPUBLISHED_PROPERTY.to_owned(), hash.to_hex())],
+        ..Default::default()
+    }
+}
+
+/// Returns the message to publish for an MQTT message from the broker, if it matches any mapping
+/// and wasn't republished by meshpit.
+fn incoming_message(mappings: &[MqttMapping], publish: &Publish) -> Option<OutgoingMessage> {
+    let is_republished = publish.properties.as_ref().is_some_and(|properties| {
+        properties
+            .user_properties
+            .iter()
+            .any(|(key, _)| key == REPUBLISHED_PROPERTY)
+    });
+    if is_republished {
+        return None;
+    }
+
+    let Ok(mqtt_topic) = String::from_utf8(publish.topic.to_vec()) else {
+        warn!("drop mqtt message with invalid topic");
+        return None;
+    };
+
+    let mapping = mappings
+        .iter()
+        .find(|mapping| matches(&mqtt_topic, &mapping.filter))?;
+
+    debug!(mqtt_topic, "received mqtt message");
+
+    let message = OutgoingMessage {
+        topic: Some(mapping.topic.clone()),
+        subject: Some(mqtt_topic),
+        payload: publish.payload.to_vec(),
+        ..Default::default()
+    };
+
+    // MQTT topics can be much longer than the subjects we accept.
+    if let Err(err) = message.validate() {
+        warn!(mqtt_topic = ?message.subject, "drop invalid mqtt message: {err}");
+        return None;
+    }
+
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use p2panda_core::Hash;
+    use rumqttc::v5::mqttbytes::v5::Publish;
+    use rumqttc::v5::mqttbytes::QoS;
+
+    use crate::topic::Topic;
+
+    use super::{incoming_message, republished_properties, MqttMapping};
+
+    fn mapping(value: &str) -> MqttMapping {
+        value.parse().unwrap()
+    }
+
+    fn topic(name: &str) -> Topic {
+        Topic::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn parse_mappings() {
+        let parsed = mapping("sensors/+/temperature=sensors");
+        assert_eq!(parsed.filter, "sensors/+/temperature");
+        assert_eq!(parsed.topic, topic("sensors"));
+
+        // Only the last "=" separates the filter from the topic.
+        let parsed = mapping("a=b/#=chat");
+        assert_eq!(parsed.filter, "a=b/#");
+        assert_eq!(parsed.topic, topic("chat"));
+
+        for value in [
+            "sensors/#",
+            "sensors/#=",
+            "sensors/#/more=chat",
+            "sensors+=chat",
+        ] {
+            assert!(value.parse::<MqttMapping>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn choose_mqtt_topic() {
+        let wildcard = mapping("sensors/+/temperature=sensors");
+        assert_eq!(
+            wildcard.mqtt_topic(Some("sensors/kitchen/temperature")),
+            Some("sensors/kitchen/temperature".to_owned())
+        );
+        assert_eq!(wildcard.mqtt_topic(Some("other/kitchen")), None);
+        assert_eq!(wildcard.mqtt_topic(None), None);
+
+        // Filters without wildcards are used when the subject doesn't match.
+        let literal = mapping("sensors/kitchen=sensors");
+        assert_eq!(
+            literal.mqtt_topic(Some("sensors/kitchen")),
+            Some("sensors/kitchen".to_owned())
+        );
+        assert_eq!(
+            literal.mqtt_topic(Some("other")),
+            Some("sensors/kitchen".to_owned())
+        );
+        assert_eq!(literal.mqtt_topic(None), Some("sensors/kitchen".to_owned()));
+    }
+
+    #[test]
+    fn publish_matching_messages() {
+        let mappings = [mapping("sensors/#=sensors"), mapping("#=other")];
+
+        let publish = Publish::new("sensors/kitchen", QoS::AtLeastOnce, "21.5", None);
+        let message = incoming_message(&mappings, &publish).unwrap();
+        assert_eq!(message.topic, Some(topic("sensors")));
+        assert_eq!(message.subject.as_deref(), Some("sensors/kitchen"));
+        assert_eq!(message.payload, b"21.5");
+
+        let publish = Publish::new("lights/kitchen", QoS::AtLeastOnce, "on", None);
+        let message = incoming_message(&mappings, &publish).unwrap();
+        assert_eq!(message.topic, Some(topic("other")));
+
+        let mappings = [mapping("sensors/#=sensors")];
+        assert!(incoming_message(&mappings, &publish).is_none());
+    }
+
+    #[test]
+    fn skip_republished_messages() {
+        let mappings = [mapping("sensors/#=sensors")];
+        let publish = Publish::new(
+            "sensors/kitchen",
+            QoS::AtLeastOnce,
+            "21.5",
+            Some(republished_properties(&Hash::new(b"21.5"))),
+        );
+        assert!(incoming_message(&mappings, &publish).is_none());
+    }
+
+    #[test]
+    fn skip_oversized_subjects() {
+        let mappings = [mapping("#=sensors")];
+        let publish = Publish::new("a".repeat(1024), QoS::AtLeastOnce, "21.5", None);
+        assert!(incoming_message(&mappings, &publish).is_none());
+    }
+}
@@ -1,8 +1,14 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use p2panda_core::{Extension, Hash, PrivateKey, PublicKey};
 use p2panda_discovery::mdns::LocalDiscovery;
 use p2panda_net::config::GossipConfig;
@@ -11,16 +17,39 @@ use p2panda_store::MemoryStore;
 use p2panda_stream::operation::{ingest_operation, IngestResult};
 use p2panda_stream::{DecodeExt, IngestExt};
 use p2panda_sync::log_sync::LogSyncProtocol;
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio::task;
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex};
+use tokio::task::JoinSet;
+use tokio::time;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, warn};
 
+use crate::bridge::{run_bridge, Bridge};
+use crate::builder::NodeBuilder;
+use crate::events::{watch_peers, Event, ObservedSync};
+use crate::fork::ForkDetector;
+use crate::fragment::{split, Reassembler, MAX_FRAGMENTS};
+use crate::http::HttpApi;
+use crate::message::{EnvelopeFormat, Message, OutgoingMessage};
+use crate::mqtt::{MqttBridge, MqttConfig};
 use crate::operation::{
-    create_operation, decode_gossip_message, encode_gossip_message, Extensions,
+    check_max_age, check_max_drift, check_operation, check_proof_of_work, create_operation,
+    decode_gossip_message, decode_header, encode_gossip_message, is_expired, mine_pow_nonce,
+    next_seq_num, Extensions, OperationOptions,
 };
+use crate::osc::OscConfig;
+use crate::pipe::{linger, Framing, PipeBridge};
+use crate::rate_limit::{AuthorRateLimiter, PublishLimit, PublishLimiter};
+use crate::tasks::TaskGroup;
+use crate::tcp::TcpServer;
 use crate::topic::{AuthorStore, LogId, Topic};
+use crate::udp::UdpBridge;
+use crate::unix::UnixBridge;
+use crate::websocket::WebSocketServer;
 
 const RELAY_ENDPOINT: &str = "https://wasser.liebechaos.org";
 
@@ -28,25 +57,99 @@ const NETWORK_ID: &str = "meshpit";
 
 const DEFAULT_TOPIC: &str = "peers-for-peers";
 
-const MAX_MESSAGE_SIZE: usize = 1000 * 10; // 10kb max. UDP payload size
+const DEFAULT_SENDER_EXPIRY: u64 = 60; // in seconds
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 1000 * 10; // 10kb max. UDP payload size
+
+// All peers need to agree on the max. size of gossip messages, otherwise they drop each other's
+// messages. This is independent of the max. message size we accept from the local application.
+const MAX_GOSSIP_MESSAGE_SIZE: usize = 1000 * 10;
+
+// Leave enough space for the header when writing payloads into operations, larger payloads are
+// split into multiple operations.
+const MAX_PAYLOAD_SIZE: usize = MAX_GOSSIP_MESSAGE_SIZE - 1000;
+
+/// Maximum size of a payload which can be published, larger payloads can't be split into enough
+/// operations.
+pub const MAX_PUBLISH_SIZE: usize = MAX_FRAGMENTS * MAX_PAYLOAD_SIZE;
+
+/// Maximum number of topics a node joins.
+///
+/// Every joined topic keeps a gossip overlay and tasks running until the node shuts down, so
+/// envelopes from the local application can't make us join an unbounded number of them.
+pub const MAX_TOPICS: usize = 64;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub topic: Topic,
-    pub udp_server_addr: SocketAddr,
-    pub udp_client_addr: SocketAddr,
+    pub local_discovery: bool,
+    pub udp_server_addr: Option<SocketAddr>,
+    pub udp_client_addrs: Vec<SocketAddr>,
+    pub udp_reply_to_senders: bool,
+    pub udp_sender_expiry: Duration,
+    pub udp_subscriptions: bool,
+    pub udp_subscription_expiry: Duration,
+    pub udp_multicast_addr: Option<SocketAddr>,
+    pub udp_multicast_ttl: u8,
+    pub udp_multicast_interface: Option<Ipv4Addr>,
+    pub udp_envelope: Option<EnvelopeFormat>,
+    pub udp_input: Option<EnvelopeFormat>,
+    pub max_message_size: usize,
+    pub osc: Option<OscConfig>,
+    pub tcp_server_addr: Option<SocketAddr>,
+    pub unix_server_path: Option<PathBuf>,
+    pub unix_client_paths: Vec<PathBuf>,
+    pub websocket_addr: Option<SocketAddr>,
+    pub allowed_origins: Vec<String>,
+    pub mqtt: Option<MqttConfig>,
+    pub http_addr: Option<SocketAddr>,
+    pub pipe: Option<Framing>,
     pub bootstrap: Option<PublicKey>,
     pub no_sync: bool,
+    pub max_author_operations: Option<u32>,
+    pub max_author_bytes: Option<u32>,
+    pub publish_limits: HashMap<Topic, PublishLimit>,
+    pub max_clock_drift: Option<Duration>,
+    pub max_gossip_age: Option<Duration>,
+    pub block_forks: bool,
+    pub pow_difficulties: HashMap<Topic, u8>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             topic: Topic::from_str(DEFAULT_TOPIC).unwrap(),
-            udp_server_addr: (Ipv4Addr::LOCALHOST, 0).into(),
-            udp_client_addr: (Ipv4Addr::LOCALHOST, 49494).into(),
+            local_discovery: true,
+            udp_server_addr: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            udp_client_addrs: vec![(Ipv4Addr::LOCALHOST, 49494).into()],
+            udp_reply_to_senders: false,
+            udp_sender_expiry: Duration::from_secs(DEFAULT_SENDER_EXPIRY),
+            udp_subscriptions: false,
+            udp_subscription_expiry: Duration::from_secs(DEFAULT_SENDER_EXPIRY),
+            udp_multicast_addr: None,
+            udp_multicast_ttl: 1,
+            udp_multicast_interface: None,
+            udp_envelope: None,
+            udp_input: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            osc: None,
+            tcp_server_addr: None,
+            unix_server_path: None,
+            unix_client_paths: Vec::new(),
+            websocket_addr: None,
+            allowed_origins: Vec::new(),
+            mqtt: None,
+            http_addr: None,
+            pipe: None,
             bootstrap: None,
             no_sync: false,
+            max_author_operations: None,
+            max_author_bytes: None,
+            publish_limits: HashMap::new(),
+            max_clock_drift: None,
+            max_gossip_age: None,
+            block_forks: false,
+            pow_difficulties: HashMap::new(),
         }
     }
 }
@@ -54,34 +157,127 @@ impl Default for Config {
 #[derive(Clone, Debug)]
 pub struct Node {
     network: Network<Topic>,
-    udp_server: Arc<UdpSocket>,
+    publish_tx: mpsc::Sender<PublishRequest>,
+    messages_tx: broadcast::Sender<Message>,
+    events_tx: broadcast::Sender<Event>,
+    publisher: Arc<Mutex<Publisher>>,
+    fork_detector: ForkDetector,
+    bridge_tasks: TaskGroup,
+    publish_tasks: TaskGroup,
+    tasks: TaskGroup,
+    udp_server_addr: Option<SocketAddr>,
+    tcp_server_addr: Option<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    stdin_closed: watch::Receiver<Option<HashSet<Topic>>>,
+    gossip_joined: watch::Receiver<HashSet<Topic>>,
     config: Config,
 }
 
 impl Node {
+    /// Validates the configuration and launches a node with it.
+    ///
+    /// Use [`NodeBuilder`] to only launch the subsystems you need.
     pub async fn new(private_key: PrivateKey, config: Config) -> Result<Self> {
-        let (to_udp_tx, mut to_udp_rx) = mpsc::channel::<Vec<u8>>(128);
+        let node = NodeBuilder::from_config(config)
+            .private_key(private_key)
+            .spawn()
+            .await?;
+        Ok(node)
+    }
+
+    /// Launches a node with an already validated configuration.
+    pub(crate) async fn spawn(private_key: PrivateKey, config: Config) -> Result<Self> {
+        let public_key = private_key.public_key();
+
+        // Messages received from the network are handed to all local bridges.
+        let (messages_tx, _) = broadcast::channel::<Message>(128);
+
+        // Everything happening in the node is reported to whoever is observing it.
+        let (events_tx, _) = broadcast::channel::<Event>(1024);
+
+        // Background tasks are stopped in stages when the node shuts down: Local bridges first,
+        // then the publisher and everything else after we've left the network.
+        let bridge_tasks = TaskGroup::new(events_tx.clone());
+        let publish_tasks = TaskGroup::new(events_tx.clone());
+        let tasks = TaskGroup::new(events_tx.clone());
+
+        // Local APIs which only receive messages join their topics via the publisher.
+        let (join_tx, join_rx) = mpsc::channel::<JoinRequest>(16);
+
+        // Bind all local servers before launching anything else, so nothing is left running when an
+        // address is already in use.
+        let udp_bridge = match config.udp_server_addr {
+            Some(addr) => Some(UdpBridge::bind(addr, &config, join_tx.clone()).await?),
+            None => None,
+        };
+        let udp_server_addr = udp_bridge.as_ref().map(UdpBridge::local_addr).transpose()?;
+
+        let tcp_listener = match config.tcp_server_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await.context("bind tcp server")?),
+            None => None,
+        };
+        let tcp_server_addr = tcp_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+
+        let websocket_listener = match config.websocket_addr {
+            Some(addr) => Some(
+                TcpListener::bind(addr)
+                    .await
+                    .context("bind websocket server")?,
+            ),
+            None => None,
+        };
+        let websocket_addr = websocket_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+
+        let http_listener = match config.http_addr {
+            Some(addr) => Some(TcpListener::bind(addr).await.context("bind http server")?),
+            None => None,
+        };
+        let http_addr = http_listener
+            .as_ref()
+            .map(TcpListener::local_addr)
+            .transpose()?;
+
+        // The socket file is bound last, it needs to be removed again when anything fails after.
+        let unix_bridge = match &config.unix_server_path {
+            Some(path) => Some(
+                UnixBridge::bind(path, &config)
+                    .await
+                    .context("bind unix socket")?,
+            ),
+            None => None,
+        };
 
         // Launch an p2p network.
         let network_id = Hash::new(NETWORK_ID.as_bytes());
 
-        let mdns = LocalDiscovery::new();
-
         let operation_store = MemoryStore::<LogId, Extensions>::new();
         let author_store = AuthorStore::new();
 
         let relay_url = RELAY_ENDPOINT.parse()?;
 
         let mut network_builder = NetworkBuilder::new(network_id.into())
-            .discovery(mdns)
             .gossip(GossipConfig {
-                max_message_size: MAX_MESSAGE_SIZE,
+                max_message_size: MAX_GOSSIP_MESSAGE_SIZE,
                 ..Default::default()
             })
             .relay(relay_url, false, 0);
 
+        if config.local_discovery {
+            network_builder = network_builder.discovery(LocalDiscovery::new());
+        }
+
         if !config.no_sync {
-            let sync_protocol = LogSyncProtocol::new(author_store.clone(), operation_store.clone());
+            let sync_protocol = ObservedSync::new(
+                LogSyncProtocol::new(author_store.clone(), operation_store.clone()),
+                events_tx.clone(),
+            );
             let sync_config = SyncConfiguration::new(sync_protocol);
             network_builder = network_builder.sync(sync_config)
         }
@@ -90,23 +286,107 @@ impl Node {
             network_builder = network_builder.direct_address(bootstrap, vec![], None);
         }
 
-        let network = network_builder.build().await.context("spawn p2p network")?;
+        let network = match network_builder.build().await {
+            Ok(network) => network,
+            Err(err) => {
+                if let Err(err) = remove_unix_socket(&config).await {
+                    error!("{err:#}");
+                }
+                return Err(err.context("spawn p2p network"));
+            }
+        };
+
+        // Everything we receive from all topics we're subscribed to is handled by one ingest
+        // pipeline.
+        let (from_network_tx, network_rx) = mpsc::channel::<FromNetwork>(128);
+
+        let mut publisher = Publisher {
+            network: network.clone(),
+            from_network_tx,
+            events_tx: events_tx.clone(),
+            tasks: tasks.clone(),
+            topics: HashMap::new(),
+            default_topic: config.topic.clone(),
+            operation_store: operation_store.clone(),
+            author_store: author_store.clone(),
+            private_key,
+            // Set max. depth of append-only log to 1 if we're not syncing.
+            prune: config.no_sync,
+            pow_difficulties: config.pow_difficulties.clone(),
+            gossip_joined: watch::Sender::new(HashSet::new()),
+        };
+        let gossip_joined = publisher.gossip_joined.subscribe();
 
-        let topic = config.topic.clone();
-        let (network_tx, network_rx, gossip_ready) = network.subscribe(topic).await?;
+        let joined = async {
+            publisher.join(&config.topic).await?;
 
-        task::spawn(async move {
-            if gossip_ready.await.is_ok() {
-                debug!("joined gossip overlay");
+            // Join the topics of all MQTT mappings right away, so we receive data from them.
+            if let Some(mqtt) = &config.mqtt {
+                for mapping in &mqtt.mappings {
+                    publisher.join(&mapping.topic).await?;
+                }
             }
-        });
 
+            anyhow::Ok(())
+        }
+        .await;
+
+        // Leave the network again when we can't join, nothing else was launched yet.
+        if let Err(err) = joined {
+            tasks.shutdown().await;
+            if let Err(err) = network.shutdown().await {
+                error!("could not leave network: {err:#}");
+            }
+            if let Err(err) = remove_unix_socket(&config).await {
+                error!("{err:#}");
+            }
+            return Err(err);
+        }
+
+        {
+            let network = network.clone();
+            let events_tx = events_tx.clone();
+            tasks.spawn("peers", async move {
+                watch_peers(network, events_tx).await;
+                Ok(())
+            });
+        }
+
+        let fork_detector = ForkDetector::new(
+            operation_store.clone(),
+            config.block_forks,
+            events_tx.clone(),
+        );
+        let max_gossip_age = config.max_gossip_age;
+        let max_clock_drift = config.max_clock_drift;
+        let pow_difficulties = config.pow_difficulties.clone();
+
+        let ingest_fork_detector = fork_detector.clone();
         let stream = ReceiverStream::new(network_rx);
-        let stream = stream.filter_map(|event| match event {
+        let gossip_events_tx = events_tx.clone();
+        let stream = stream.filter_map(move |event| match event {
             FromNetwork::GossipMessage { bytes, .. } => match decode_gossip_message(&bytes) {
-                Ok(result) => Some(result),
+                Ok((header, body)) => {
+                    // Old data should only arrive via sync, reject it when it comes in via the
+                    // live gossip overlay.
+                    if let Some(max_age) = max_gossip_age {
+                        let result = match decode_header(&header) {
+                            Ok(header) => check_max_age(&header, max_age)
+                                .map_err(|err| (Some(header.public_key), err)),
+                            Err(err) => Err((None, err.into())),
+                        };
+                        if let Err((public_key, err)) = result {
+                            warn!("reject gossip message: {err}");
+                            report_rejected(&gossip_events_tx, public_key, err);
+                            return None;
+                        }
+                    }
+
+                    Some((header, body))
+                }
                 Err(err) => {
                     warn!("could not decode gossip message: {err}");
+                    report_rejected(&gossip_events_tx, None, err);
                     None
                 }
             },
@@ -116,34 +396,116 @@ impl Node {
         });
 
         // Decode and ingest the p2panda operations.
-        let mut stream = stream
+        let decode_events_tx = events_tx.clone();
+        let validate_events_tx = events_tx.clone();
+        let drift_events_tx = events_tx.clone();
+        let pow_events_tx = events_tx.clone();
+        let fork_events_tx = events_tx.clone();
+        let ingest_events_tx = events_tx.clone();
+        let stream = stream
             .decode()
-            .filter_map(|result| match result {
+            .filter_map(move |result| match result {
                 Ok(operation) => Some(operation),
                 Err(err) => {
                     warn!("decode operation error: {err}");
+                    report_rejected(&decode_events_tx, None, err);
                     None
                 }
             })
+            // Ingest validates operations as well, but doesn't tell us their author when they are
+            // invalid. Check them before, so the other checks only see signed operations.
+            .filter(move |(header, body, _)| {
+                if let Err(err) = check_operation(header, body.as_ref()) {
+                    warn!(public_key = %header.public_key, "reject operation: {err}");
+                    report_rejected(&validate_events_tx, Some(header.public_key), err);
+                    return false;
+                }
+                true
+            })
+            .filter(move |(header, _, _)| {
+                let Some(max_drift) = max_clock_drift else {
+                    return true;
+                };
+                match check_max_drift(header, max_drift) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!(public_key = %header.public_key, "reject operation: {err}");
+                        report_rejected(&drift_events_tx, Some(header.public_key), err);
+                        false
+                    }
+                }
+            })
+            .filter(move |(header, _, _)| {
+                // Operations without extensions are rejected during ingest.
+                let Some(topic) = header.extensions.as_ref().map(Extensions::topic) else {
+                    return true;
+                };
+                let Some(difficulty) = pow_difficulties.get(&topic) else {
+                    return true;
+                };
+                match check_proof_of_work(header, *difficulty) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!(public_key = %header.public_key, "reject operation: {err}");
+                        report_rejected(&pow_events_tx, Some(header.public_key), err);
+                        false
+                    }
+                }
+            })
+            .then(move |operation| {
+                let fork_detector = ingest_fork_detector.clone();
+                let fork_events_tx = fork_events_tx.clone();
+                async move {
+                    match fork_detector.check(&operation.0).await {
+                        Ok(()) => Some(operation),
+                        Err(err) => {
+                            report_rejected(&fork_events_tx, Some(operation.0.public_key), err);
+                            None
+                        }
+                    }
+                }
+            })
+            .filter_map(|operation| operation)
             .ingest(operation_store.clone(), 128)
-            .filter_map(|result| match result {
+            .filter_map(move |result| match result {
                 Ok(operation) => Some(operation),
                 Err(err) => {
                     warn!("ingest operation error: {err}");
+                    report_rejected(&ingest_events_tx, None, err);
                     None
                 }
             });
 
         {
             let mut author_store = author_store.clone();
+            let mut rate_limiter =
+                AuthorRateLimiter::new(config.max_author_operations, config.max_author_bytes);
+            let mut reassembler = Reassembler::new();
+            let messages_tx = messages_tx.clone();
+            let events_tx = events_tx.clone();
+
+            tasks.spawn("ingest", async move {
+                let mut stream = pin!(stream);
 
-            task::spawn(async move {
                 while let Some(operation) = stream.next().await {
-                    let log_id: Option<LogId> = operation.header.extract();
-                    let topic = Topic::new(log_id.expect("log id exists in header extensions"));
-                    author_store
-                        .add_author(topic, operation.header.public_key)
-                        .await;
+                    let extensions = operation
+                        .header
+                        .extensions
+                        .as_ref()
+                        .expect("extensions exist in header");
+                    let topic = extensions.topic();
+                    let fragment = extensions.fragment();
+                    let log_id: LogId = operation
+                        .header
+                        .extract()
+                        .expect("log id exists in header extensions");
+
+                    // Ephemeral logs are never synced, so we don't need to remember their authors.
+                    if !extensions.is_ephemeral() {
+                        author_store
+                            .add_author(topic.clone(), operation.header.public_key)
+                            .await;
+                    }
 
                     let body_len = operation.body.as_ref().map_or(0, |body| body.size());
                     debug!(
@@ -153,118 +515,278 @@ impl Node {
                         "received operation"
                     );
 
+                    let _ = events_tx.send(Event::OperationReceived {
+                        topic: topic.clone(),
+                        public_key: operation.header.public_key,
+                        hash: operation.hash,
+                        seq_num: operation.header.seq_num,
+                    });
+
+                    if is_expired(&operation.header) {
+                        debug!(hash = %operation.hash, "operation expired, don't forward");
+                        continue;
+                    }
+
                     match operation.body {
                         Some(body) => {
-                            if to_udp_tx.send(body.to_bytes()).await.is_err() {
-                                break;
+                            let message = Message::from_operation(&operation.header, &body);
+
+                            let public_key = message.public_key;
+                            let message = match reassembler.push(log_id, fragment, message) {
+                                Ok(Some(message)) => message,
+                                Ok(None) => continue,
+                                Err(err) => {
+                                    warn!(%public_key, "drop fragment: {err}");
+                                    report_rejected(&events_tx, Some(public_key), err);
+                                    continue;
+                                }
+                            };
+
+                            // Payloads which were split into multiple operations count as one.
+                            if !rate_limiter.check(&public_key, message.payload.len()) {
+                                report_rejected(
+                                    &events_tx,
+                                    Some(public_key),
+                                    "author exceeded rate limit",
+                                );
+                                continue;
                             }
+
+                            // Sending only fails when no bridge is listening, we can ignore that.
+                            let _ = messages_tx.send(message);
                         }
                         None => {
                             continue;
                         }
                     }
                 }
+
+                Ok(())
             });
         }
 
-        // Launch an UDP server which listens for incoming UDP packets of any data.
-        let udp_server = UdpSocket::bind(config.udp_server_addr)
-            .await
-            .context("bind udp server")?;
-        let udp_server = Arc::new(udp_server);
+        // Publish messages from all local bridges in one place, so the publish rate limit applies to
+        // all of them.
+        let (publish_tx, publish_rx) = mpsc::channel::<PublishRequest>(128);
+
+        let publisher = Arc::new(Mutex::new(publisher));
 
         {
-            let mut operation_store = operation_store.clone();
-            let mut author_store = author_store;
-            let udp_server = udp_server.clone();
-            let log_id = config.topic.id();
-
-            task::spawn(async move {
-                let mut buf = [0; MAX_MESSAGE_SIZE];
-
-                loop {
-                    tokio::select! {
-                        message = udp_server.recv(&mut buf) => {
-                            match message {
-                                Ok(len) => {
-                                    // Set max. depth of append-only log to 1 if we're not syncing.
-                                    let prune = config.no_sync;
-
-                                    let (header, body) = create_operation(
-                                        &mut operation_store,
-                                        log_id,
-                                        &private_key,
-                                        Some(&buf[..len]),
-                                        prune,
-                                    )
-                                    .await;
-
-                                    let Ok(gossip_message_bytes) = encode_gossip_message(&header, body.as_ref()) else {
-                                        error!("could not encode gossip message");
-                                        break;
-                                    };
-                                    let header_bytes = header.to_bytes();
-
-                                    let Ok(result) = ingest_operation(
-                                        &mut operation_store,
-                                        header,
-                                        body,
-                                        header_bytes,
-                                        &log_id,
-                                        prune,
-                                    )
-                                    .await else {
-                                        error!("could not ingest p2panda operation");
-                                        break;
-                                    };
-
-                                    match result {
-                                        IngestResult::Complete(operation) => {
-                                            author_store
-                                                .add_author(
-                                                    Topic::new(log_id),
-                                                    operation.header.public_key
-                                                )
-                                                .await;
-
-                                            debug!(
-                                                seq_num = operation.header.seq_num,
-                                                len = len,
-                                                hash = %operation.hash,
-                                                "publish operation"
-                                            );
-                                        },
-                                        _ => unreachable!(),
-                                    }
-
-                                    if network_tx.send(ToNetwork::Message {
-                                        bytes: gossip_message_bytes,
-                                    }).await.is_err() {
-                                        break;
-                                    }
-                                }
-                                Err(err) => {
-                                    error!("udp server error on recv: {err}");
-                                }
-                            }
-                        }
-                        Some(message) = to_udp_rx.recv() => {
-                            if let Err(err) = udp_server.send_to(&message, config.udp_client_addr).await {
-                                error!("udp error on send to client: {err}");
-                            }
-                        }
-                    }
-                }
+            let publisher = publisher.clone();
+            publish_tasks.spawn_graceful("joiner", |shutdown| {
+                run_joiner(publisher, join_rx, shutdown)
+            });
+        }
+
+        {
+            let publish_limiter = PublishLimiter::new(config.publish_limits.clone());
+            let publisher = PowPublisher::new(publisher.clone(), &config.pow_difficulties);
+            let default_topic = config.topic.clone();
+
+            publish_tasks.spawn_graceful("publisher", |shutdown| {
+                run_publisher(
+                    publisher,
+                    publish_rx,
+                    publish_limiter,
+                    default_topic,
+                    shutdown,
+                )
             });
         }
 
+        // Launch an UDP server which listens for incoming UDP packets of any data.
+        if let Some(udp_bridge) = udp_bridge {
+            spawn_bridge(
+                &bridge_tasks,
+                udp_bridge,
+                &publish_tx,
+                &messages_tx,
+                &events_tx,
+            );
+        }
+
+        // Optionally launch a TCP server for applications which want to send larger messages
+        // reliably.
+        if let Some(listener) = tcp_listener {
+            TcpServer {
+                publish_tx: publish_tx.clone(),
+                messages_tx: messages_tx.clone(),
+                events_tx: events_tx.clone(),
+                input: config.udp_input,
+                envelope: config.udp_envelope,
+            }
+            .spawn(listener, &bridge_tasks);
+        }
+
+        // Optionally launch an Unix socket for applications on the same host.
+        if let Some(unix_bridge) = unix_bridge {
+            spawn_bridge(
+                &bridge_tasks,
+                unix_bridge,
+                &publish_tx,
+                &messages_tx,
+                &events_tx,
+            );
+        }
+
+        // Optionally launch a WebSocket server for browser-based applications.
+        if let Some(listener) = websocket_listener {
+            WebSocketServer {
+                publish_tx: publish_tx.clone(),
+                messages_tx: messages_tx.clone(),
+                events_tx: events_tx.clone(),
+                input: config.udp_input,
+                envelope: config.udp_envelope,
+                allowed_origins: config.allowed_origins.clone(),
+            }
+            .spawn(listener, &bridge_tasks);
+        }
+
+        // Optionally launch a HTTP API for scripts and web dashboards.
+        if let Some(listener) = http_listener {
+            HttpApi {
+                publish_tx: publish_tx.clone(),
+                join_tx: join_tx.clone(),
+                messages_tx: messages_tx.clone(),
+                events_tx: events_tx.clone(),
+                operation_store: operation_store.clone(),
+                author_store: author_store.clone(),
+                allowed_origins: config.allowed_origins.clone(),
+            }
+            .spawn(listener, &bridge_tasks);
+        }
+
+        // Optionally connect to a MQTT broker.
+        if let Some(mqtt) = &config.mqtt {
+            let mqtt_bridge = MqttBridge::connect(
+                mqtt,
+                &format!("meshpit-{}", &public_key.to_hex()[..16]),
+                events_tx.clone(),
+                &bridge_tasks,
+            );
+            spawn_bridge(
+                &bridge_tasks,
+                mqtt_bridge,
+                &publish_tx,
+                &messages_tx,
+                &events_tx,
+            );
+        }
+
+        // Optionally read messages from stdin and write received ones to stdout.
+        let (stdin_closed_tx, stdin_closed) = watch::channel(None);
+        if let Some(framing) = config.pipe {
+            let pipe_bridge = PipeBridge::new(
+                io::stdin(),
+                framing,
+                config.udp_input,
+                config.udp_envelope,
+                config.topic.clone(),
+                stdin_closed_tx,
+                &bridge_tasks,
+            );
+            spawn_bridge(
+                &bridge_tasks,
+                pipe_bridge,
+                &publish_tx,
+                &messages_tx,
+                &events_tx,
+            );
+        }
+
         Ok(Self {
             network,
-            udp_server,
+            publish_tx,
+            messages_tx,
+            events_tx,
+            publisher,
+            fork_detector,
+            bridge_tasks,
+            publish_tasks,
+            tasks,
+            udp_server_addr,
+            tcp_server_addr,
+            websocket_addr,
+            http_addr,
+            stdin_closed,
+            gossip_joined,
             config,
         })
     }
 
+    /// Publishes the payload on the given topic and returns the hash of the created operation.
+    ///
+    /// Payloads which don't fit into one operation are split into multiple ones, in this case the
+    /// hash of the first operation is returned. Other peers receive the message with the same hash.
+    pub async fn publish(&self, topic: &Topic, payload: impl Into<Vec<u8>>) -> Result<Hash> {
+        let mut message = OutgoingMessage::new(payload.into());
+        message.topic = Some(topic.clone());
+        self.publish_message(message).await
+    }
+
+    /// Publishes the message with all given options and returns the hash of the created operation.
+    ///
+    /// Unlike messages from local bridges, messages published with this method are not held back
+    /// by the publish rate limit.
+    pub async fn publish_message(&self, message: OutgoingMessage) -> Result<Hash> {
+        message.validate()?;
+        publish_message(&self.publisher, &message).await
+    }
+
+    /// Subscribes to the topic and returns a stream of all messages we receive from other peers on
+    /// it.
+    ///
+    /// Messages published by this node are not part of the stream.
+    pub async fn subscribe(&self, topic: &Topic) -> Result<impl Stream<Item = Message>> {
+        // Subscribe to the stream before joining the topic so we don't miss any messages.
+        let messages_rx = self.messages_tx.subscribe();
+        self.publisher.lock().await.join(topic).await?;
+
+        let topic = topic.clone();
+        let stream = BroadcastStream::new(messages_rx).filter_map(move |message| match message {
+            Ok(message) if message.topic == topic => Some(message),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!(%topic, skipped, "subscription lagged behind, skip messages");
+                None
+            }
+        });
+
+        Ok(stream)
+    }
+
+    /// Returns a stream of everything happening in the node from now on.
+    pub fn events(&self) -> impl Stream<Item = Event> {
+        BroadcastStream::new(self.events_tx.subscribe()).filter_map(|event| match event {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                warn!(skipped, "event stream lagged behind, skip events");
+                None
+            }
+        })
+    }
+
+    /// Returns all authors we've blocked because they forked their log.
+    ///
+    /// Authors are only blocked when blocking forks is enabled in the configuration.
+    pub async fn blocked_authors(&self) -> Vec<PublicKey> {
+        self.fork_detector.blocked_authors().await
+    }
+
+    /// Connects local applications via the given bridge.
+    ///
+    /// Everything the bridge receives is published and all messages we receive from the network
+    /// are delivered to it.
+    pub fn add_bridge(&self, bridge: impl Bridge) {
+        spawn_bridge(
+            &self.bridge_tasks,
+            bridge,
+            &self.publish_tx,
+            &self.messages_tx,
+            &self.events_tx,
+        );
+    }
+
     pub async fn addrs(&self) -> Result<Vec<SocketAddr>> {
         let node_addrs = self
             .network
@@ -274,17 +796,643 @@ impl Node {
         Ok(node_addrs)
     }
 
-    pub async fn udp_server_addr(&self) -> Result<SocketAddr> {
-        let server_addr = self.udp_server.local_addr()?;
-        Ok(server_addr)
+    pub fn udp_server_addr(&self) -> Option<SocketAddr> {
+        self.udp_server_addr
+    }
+
+    pub fn udp_client_addrs(&self) -> &[SocketAddr] {
+        &self.config.udp_client_addrs
+    }
+
+    pub fn udp_reply_to_senders(&self) -> bool {
+        self.config.udp_reply_to_senders
+    }
+
+    pub fn udp_multicast_addr(&self) -> Option<SocketAddr> {
+        self.config.udp_multicast_addr
+    }
+
+    pub fn tcp_server_addr(&self) -> Option<SocketAddr> {
+        self.tcp_server_addr
     }
 
-    pub fn udp_client_addr(&self) -> SocketAddr {
-        self.config.udp_client_addr
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
     }
 
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_addr
+    }
+
+    pub fn unix_server_path(&self) -> Option<&Path> {
+        self.config.unix_server_path.as_deref()
+    }
+
+    /// Waits until stdin was closed and all messages read from it were handed over for publishing,
+    /// returns the topics they were published to.
+    ///
+    /// Never returns when the pipe bridge is not used.
+    pub async fn stdin_closed(&self) -> HashSet<Topic> {
+        let mut stdin_closed = self.stdin_closed.clone();
+        let topics = match stdin_closed.wait_for(Option::is_some).await {
+            Ok(topics) => topics.clone().unwrap_or_default(),
+            Err(_) => std::future::pending().await,
+        };
+        topics
+    }
+
+    /// Gives other peers the chance to receive the messages we've published to the topics before
+    /// we leave.
+    ///
+    /// Messages published before we joined the gossip overlay of a topic only reach other peers
+    /// via sync. We wait until we joined the overlays of all topics and then linger for the given
+    /// duration, so peers we met there can sync with us.
+    pub async fn linger(&self, topics: &HashSet<Topic>, duration: Duration) {
+        linger(self.gossip_joined.clone(), topics, duration).await;
+    }
+
+    pub fn unix_client_paths(&self) -> &[PathBuf] {
+        &self.config.unix_client_paths
+    }
+
+    /// Stops the node gracefully.
+    ///
+    /// Local bridges stop receiving and deliver what is left, then all pending messages are
+    /// published before we leave the network. Background tasks which don't stop in time are
+    /// aborted. All stages run even if an earlier one failed, the returned error names everything
+    /// which went wrong.
     pub async fn shutdown(self) -> Result<()> {
-        self.network.shutdown().await?;
+        let mut failures = self.bridge_tasks.shutdown().await;
+        failures.extend(self.publish_tasks.shutdown().await);
+
+        let mut errors = Vec::new();
+        if let Err(err) = self.network.shutdown().await {
+            error!("could not leave network: {err:#}");
+            errors.push(format!("network: {err:#}"));
+        }
+
+        failures.extend(self.tasks.shutdown().await);
+
+        if let Err(err) = remove_unix_socket(&self.config).await {
+            error!("{err:#}");
+            errors.push(format!("{err:#}"));
+        }
+
+        errors.extend(
+            failures
+                .iter()
+                .map(|failure| format!("{}: {:#}", failure.name, failure.error)),
+        );
+
+        if !errors.is_empty() {
+            bail!(
+                "{} error(s) during shutdown: {}",
+                errors.len(),
+                errors.join("; ")
+            );
+        }
+
         Ok(())
     }
 }
+
+/// Removes the socket file of the Unix socket bridge, if there is one.
+async fn remove_unix_socket(config: &Config) -> Result<()> {
+    let Some(path) = &config.unix_server_path else {
+        return Ok(());
+    };
+
+    // The socket file might have been removed by someone else already.
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("remove unix socket {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Connects the bridge to the node and stops it with the given task group.
+fn spawn_bridge(
+    tasks: &TaskGroup,
+    bridge: impl Bridge,
+    publish_tx: &mpsc::Sender<PublishRequest>,
+    messages_tx: &broadcast::Sender<Message>,
+    events_tx: &broadcast::Sender<Event>,
+) {
+    let publish_tx = publish_tx.clone();
+    let messages_rx = messages_tx.subscribe();
+    let events_tx = events_tx.clone();
+    tasks.spawn_graceful(bridge.name(), |shutdown| {
+        run_bridge(bridge, publish_tx, messages_rx, events_tx, shutdown)
+    });
+}
+
+/// Message from a local bridge which waits to be published.
+#[derive(Debug)]
+pub struct PublishRequest {
+    pub message: OutgoingMessage,
+
+    /// Receives the hash of the published operation. Dropped without a reply when the publish
+    /// rate limit dropped the message.
+    pub reply_tx: Option<oneshot::Sender<Result<Hash>>>,
+}
+
+/// Request of a local API to join a topic, so it receives messages of the topic without publishing
+/// to it first.
+#[derive(Debug)]
+pub struct JoinRequest {
+    pub topic: Topic,
+
+    /// Receives the result, joining fails with [`TooManyTopics`] when we already joined
+    /// [`MAX_TOPICS`] topics.
+    pub reply_tx: oneshot::Sender<Result<()>>,
+}
+
+/// Joins the topic via the node's publisher, the same way [`Node::subscribe`] does.
+pub async fn join_topic(join_tx: &mpsc::Sender<JoinRequest>, topic: &Topic) -> Result<()> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    join_tx
+        .send(JoinRequest {
+            topic: topic.clone(),
+            reply_tx,
+        })
+        .await
+        .map_err(|_| anyhow!("node is shutting down"))?;
+    reply_rx
+        .await
+        .map_err(|_| anyhow!("node is shutting down"))?
+}
+
+/// Error when joining another topic would exceed [`MAX_TOPICS`].
+#[derive(Debug)]
+pub struct TooManyTopics(pub Topic);
+
+impl fmt::Display for TooManyTopics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can not join topic {}, already joined {MAX_TOPICS} topics",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for TooManyTopics {}
+
+impl From<OutgoingMessage> for PublishRequest {
+    fn from(message: OutgoingMessage) -> Self {
+        Self {
+            message,
+            reply_tx: None,
+        }
+    }
+}
+
+/// Publishes messages from local bridges while respecting the publish rate limits of their topics.
+///
+/// On shutdown all messages which are still pending are published regardless of the limits.
+async fn run_publisher(
+    mut publisher: PowPublisher,
+    mut publish_rx: mpsc::Receiver<PublishRequest>,
+    mut publish_limiter: PublishLimiter<PublishRequest>,
+    default_topic: Topic,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let next_ready = publish_limiter.next_ready();
+
+        tokio::select! {
+            request = publish_rx.recv() => {
+                let Some(request) = request else {
+                    break;
+                };
+
+                let topic = request.message.topic.as_ref().unwrap_or(&default_topic).clone();
+                let Some(request) = publish_limiter.push(&topic, request) else {
+                    continue;
+                };
+
+                publisher.publish(&topic, request).await;
+            }
+            _ = time::sleep_until(next_ready.unwrap_or_else(Instant::now).into()), if next_ready.is_some() => {
+                while let Some(request) = publish_limiter.pop_ready() {
+                    let topic = request.message.topic.as_ref().unwrap_or(&default_topic).clone();
+                    publisher.publish(&topic, request).await;
+                }
+            }
+            _ = shutdown.cancelled() => {
+                break;
+            }
+        }
+    }
+
+    publish_rx.close();
+    let mut pending = Vec::new();
+    while let Some(request) = publish_rx.recv().await {
+        let topic = request
+            .message
+            .topic
+            .as_ref()
+            .unwrap_or(&default_topic)
+            .clone();
+        pending.extend(publish_limiter.push(&topic, request));
+    }
+    pending.extend(publish_limiter.drain());
+
+    if !pending.is_empty() {
+        debug!(count = pending.len(), "publish pending messages");
+    }
+
+    for request in pending {
+        let topic = request
+            .message
+            .topic
+            .as_ref()
+            .unwrap_or(&default_topic)
+            .clone();
+        publisher.publish(&topic, request).await;
+    }
+
+    publisher.finish().await;
+
+    Ok(())
+}
+
+/// Max. number of messages waiting for their proof-of-work stamp per topic.
+const MAX_POW_QUEUE_LEN: usize = 16;
+
+/// Publishes messages of topics which require proof-of-work stamps in a separate task per topic.
+///
+/// Mining a stamp can take a while, this way it doesn't hold back the messages of other topics and
+/// the publish queue of the bridges doesn't fill up meanwhile. Messages of a topic are still
+/// published in order, when too many of them are waiting they are rejected.
+struct PowPublisher {
+    publisher: Arc<Mutex<Publisher>>,
+    pow_topics: HashSet<Topic>,
+    queues: HashMap<Topic, mpsc::Sender<PublishRequest>>,
+    workers: JoinSet<()>,
+}
+
+impl PowPublisher {
+    fn new(publisher: Arc<Mutex<Publisher>>, pow_difficulties: &HashMap<Topic, u8>) -> Self {
+        Self {
+            publisher,
+            pow_topics: pow_difficulties.keys().cloned().collect(),
+            queues: HashMap::new(),
+            workers: JoinSet::new(),
+        }
+    }
+
+    async fn publish(&mut self, topic: &Topic, request: PublishRequest) {
+        if !self.pow_topics.contains(topic) {
+            publish_request(&self.publisher, request).await;
+            return;
+        }
+
+        let queue = self.queues.entry(topic.clone()).or_insert_with(|| {
+            let (queue_tx, mut queue_rx) = mpsc::channel(MAX_POW_QUEUE_LEN);
+            let publisher = self.publisher.clone();
+            self.workers.spawn(async move {
+                while let Some(request) = queue_rx.recv().await {
+                    publish_request(&publisher, request).await;
+                }
+            });
+            queue_tx
+        });
+
+        if let Err(err) = queue.try_send(request) {
+            warn!(%topic, "too many messages waiting for proof-of-work, drop message");
+            let request = match err {
+                TrySendError::Full(request) | TrySendError::Closed(request) => request,
+            };
+            if let Some(reply_tx) = request.reply_tx {
+                let _ = reply_tx.send(Err(anyhow!(
+                    "too many messages waiting for proof-of-work in topic {topic}"
+                )));
+            }
+        }
+    }
+
+    /// Waits until all queued messages are published.
+    async fn finish(mut self) {
+        self.queues.clear();
+        while self.workers.join_next().await.is_some() {}
+    }
+}
+
+/// Joins topics on request of local APIs until the node shuts down.
+async fn run_joiner(
+    publisher: Arc<Mutex<Publisher>>,
+    mut join_rx: mpsc::Receiver<JoinRequest>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    loop {
+        let request = tokio::select! {
+            request = join_rx.recv() => request,
+            _ = shutdown.cancelled() => None,
+        };
+        let Some(request) = request else {
+            break;
+        };
+
+        let result = publisher.lock().await.join(&request.topic).await;
+        let _ = request.reply_tx.send(result.map(|_| ()));
+    }
+
+    Ok(())
+}
+
+/// Publishes the message of a local bridge and replies with the result if asked to.
+async fn publish_request(publisher: &Mutex<Publisher>, request: PublishRequest) {
+    let result = publish_message(publisher, &request.message).await;
+    if let Err(err) = &result {
+        error!("could not publish message: {err}");
+    }
+    if let Some(reply_tx) = request.reply_tx {
+        let _ = reply_tx.send(result);
+    }
+}
+
+/// Reports data from another peer we didn't accept.
+fn report_rejected(
+    events_tx: &broadcast::Sender<Event>,
+    public_key: Option<PublicKey>,
+    reason: impl ToString,
+) {
+    let _ = events_tx.send(Event::OperationRejected {
+        public_key,
+        reason: reason.to_string(),
+    });
+}
+
+/// Publishes the message with the publisher and returns the hash of the (first) operation.
+///
+/// Mining proof-of-work stamps can take minutes, we don't hold the lock on the publisher meanwhile
+/// so other bridges can still publish and join topics.
+async fn publish_message(publisher: &Mutex<Publisher>, message: &OutgoingMessage) -> Result<Hash> {
+    loop {
+        let mut locked = publisher.lock().await;
+        let prepared = locked.prepare(message).await?;
+        let Some(difficulty) = prepared.pow_difficulty else {
+            return locked.publish(message, &prepared, None).await;
+        };
+
+        let public_key = locked.private_key.public_key();
+        let seq_num = locked.next_seq_num(&prepared.log_id).await;
+        drop(locked);
+
+        // Stamps are bound to the position of the operation in the log, fragments are published
+        // in a row.
+        let mut pow_nonces = Vec::new();
+        for (index, (payload, _)) in split(&message.payload, MAX_PAYLOAD_SIZE)
+            .into_iter()
+            .enumerate()
+        {
+            let nonce = mine_pow_nonce(
+                public_key,
+                prepared.log_id,
+                seq_num + index as u64,
+                payload,
+                difficulty,
+            )
+            .await;
+            pow_nonces.push(nonce);
+        }
+
+        let mut locked = publisher.lock().await;
+        if locked.next_seq_num(&prepared.log_id).await != seq_num {
+            debug!(topic = %prepared.topic, "log changed while mining proof-of-work, mine again");
+            continue;
+        }
+        return locked.publish(message, &prepared, Some(pow_nonces)).await;
+    }
+}
+
+/// Topic and log a message is published to.
+#[derive(Debug)]
+struct PreparedMessage {
+    topic: Topic,
+    network_tx: mpsc::Sender<ToNetwork>,
+    log_id: LogId,
+    prune: bool,
+    pow_difficulty: Option<u8>,
+}
+
+/// Turns messages from the local application into operations in our logs.
+///
+/// Topics which were not joined yet are subscribed to on demand.
+#[derive(Debug)]
+struct Publisher {
+    network: Network<Topic>,
+    from_network_tx: mpsc::Sender<FromNetwork>,
+    events_tx: broadcast::Sender<Event>,
+    tasks: TaskGroup,
+    topics: HashMap<Topic, mpsc::Sender<ToNetwork>>,
+    default_topic: Topic,
+    operation_store: MemoryStore<LogId, Extensions>,
+    author_store: AuthorStore,
+    private_key: PrivateKey,
+    prune: bool,
+    pow_difficulties: HashMap<Topic, u8>,
+    gossip_joined: watch::Sender<HashSet<Topic>>,
+}
+
+impl Publisher {
+    /// Joins the topic if we're not subscribed to it yet and forwards everything we receive on it
+    /// to the ingest pipeline.
+    async fn join(&mut self, topic: &Topic) -> Result<mpsc::Sender<ToNetwork>> {
+        if let Some(network_tx) = self.topics.get(topic) {
+            return Ok(network_tx.clone());
+        }
+
+        if self.topics.len() >= MAX_TOPICS {
+            return Err(TooManyTopics(topic.clone()).into());
+        }
+
+        let (network_tx, mut network_rx, gossip_ready) =
+            self.network.subscribe(topic.clone()).await?;
+
+        {
+            let topic = topic.clone();
+            let events_tx = self.events_tx.clone();
+            let gossip_joined = self.gossip_joined.clone();
+            self.tasks.spawn("gossip", async move {
+                if gossip_ready.await.is_ok() {
+                    debug!(%topic, "joined gossip overlay");
+                    gossip_joined.send_modify(|joined| {
+                        joined.insert(topic.clone());
+                    });
+                    let _ = events_tx.send(Event::GossipJoined { topic });
+                }
+                Ok(())
+            });
+        }
+
+        // Forward everything we receive on the topic to the ingest pipeline.
+        let from_network_tx = self.from_network_tx.clone();
+        self.tasks.spawn("subscription", async move {
+            while let Some(event) = network_rx.recv().await {
+                if from_network_tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        self.topics.insert(topic.clone(), network_tx.clone());
+        Ok(network_tx)
+    }
+
+    /// Joins the topic of the message and finds out where it is published.
+    async fn prepare(&mut self, message: &OutgoingMessage) -> Result<PreparedMessage> {
+        let topic = message
+            .topic
+            .clone()
+            .unwrap_or_else(|| self.default_topic.clone());
+
+        let network_tx = self.join(&topic).await?;
+
+        // Ephemeral messages are written to a separate log which only keeps the latest message.
+        let (log_id, prune) = if message.ephemeral {
+            (topic.ephemeral_log_id(), true)
+        } else {
+            (topic.id(), self.prune)
+        };
+
+        if message.payload.len() > MAX_PUBLISH_SIZE {
+            bail!(
+                "payload of {} bytes is too large to be published",
+                message.payload.len()
+            );
+        }
+
+        Ok(PreparedMessage {
+            pow_difficulty: self.pow_difficulties.get(&topic).copied(),
+            topic,
+            network_tx,
+            log_id,
+            prune,
+        })
+    }
+
+    async fn next_seq_num(&self, log_id: &LogId) -> u64 {
+        next_seq_num(
+            &self.operation_store,
+            &self.private_key.public_key(),
+            log_id,
+        )
+        .await
+    }
+
+    /// Creates, signs and stores new operations with the given message in our log and broadcasts
+    /// them to all peers in the gossip overlay.
+    ///
+    /// Proof-of-work stamps need to be given for every operation when the topic requires them.
+    /// Returns the hash of the (first) operation.
+    async fn publish(
+        &mut self,
+        message: &OutgoingMessage,
+        prepared: &PreparedMessage,
+        pow_nonces: Option<Vec<u64>>,
+    ) -> Result<Hash> {
+        // Payloads which don't fit into one operation are split into multiple ones.
+        let mut first_hash = None;
+        for (index, (payload, fragment)) in split(&message.payload, MAX_PAYLOAD_SIZE)
+            .into_iter()
+            .enumerate()
+        {
+            let hash = self
+                .publish_operation(
+                    &prepared.topic,
+                    &prepared.network_tx,
+                    prepared.log_id,
+                    payload,
+                    OperationOptions {
+                        prune: prepared.prune,
+                        ephemeral: message.ephemeral,
+                        ttl: message.ttl,
+                        content_type: message.content_type.clone(),
+                        subject: message.subject.clone(),
+                        pow_nonce: pow_nonces.as_ref().map(|nonces| nonces[index]),
+                        fragment,
+                    },
+                )
+                .await?;
+            first_hash.get_or_insert(hash);
+        }
+
+        Ok(first_hash.expect("at least one operation was published"))
+    }
+
+    async fn publish_operation(
+        &mut self,
+        topic: &Topic,
+        network_tx: &mpsc::Sender<ToNetwork>,
+        log_id: LogId,
+        payload: &[u8],
+        options: OperationOptions,
+    ) -> Result<Hash> {
+        let prune = options.prune;
+        let ephemeral = options.ephemeral;
+
+        let (header, body) = create_operation(
+            &mut self.operation_store,
+            log_id,
+            &self.private_key,
+            Some(payload),
+            options,
+        )
+        .await;
+
+        let gossip_message_bytes = encode_gossip_message(&header, body.as_ref())
+            .context("could not encode gossip message")?;
+        let header_bytes = header.to_bytes();
+
+        let result = ingest_operation(
+            &mut self.operation_store,
+            header,
+            body,
+            header_bytes,
+            &log_id,
+            prune,
+        )
+        .await
+        .context("could not ingest p2panda operation")?;
+
+        let operation = match result {
+            IngestResult::Complete(operation) => operation,
+            IngestResult::Retry(_, _, _, missing) => {
+                bail!("could not ingest p2panda operation, {missing} operations missing in our log")
+            }
+        };
+
+        if !ephemeral {
+            self.author_store
+                .add_author(topic.clone(), operation.header.public_key)
+                .await;
+        }
+
+        debug!(
+            %topic,
+            seq_num = operation.header.seq_num,
+            len = payload.len(),
+            hash = %operation.hash,
+            "publish operation"
+        );
+
+        let _ = self.events_tx.send(Event::OperationPublished {
+            topic: topic.clone(),
+            hash: operation.hash,
+            seq_num: operation.header.seq_num,
+        });
+
+        network_tx
+            .send(ToNetwork::Message {
+                bytes: gossip_message_bytes,
+            })
+            .await
+            .context("could not send gossip message to network")?;
+
+        Ok(operation.hash)
+    }
+}
@@ -1,11 +1,18 @@
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use anyhow::{bail, Result};
 use p2panda_core::cbor::{decode_cbor, encode_cbor, DecodeError, EncodeError};
-use p2panda_core::{Body, Extension, Header, PrivateKey, PruneFlag};
+use p2panda_core::{
+    validate_header, Body, Extension, Hash, Header, PrivateKey, PruneFlag, PublicKey,
+};
 use p2panda_store::{LocalLogStore, MemoryStore};
 use serde::{Deserialize, Serialize};
+use tokio::task;
 
-use crate::topic::LogId;
+use crate::fragment::Fragment;
+use crate::topic::{LogId, Topic};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Extensions {
@@ -17,6 +24,51 @@ pub struct Extensions {
         default = "PruneFlag::default"
     )]
     prune_flag: PruneFlag,
+
+    #[serde(rename = "pow", skip_serializing_if = "Option::is_none", default)]
+    pow_nonce: Option<u64>,
+
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    ephemeral: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    ttl: Option<u64>,
+
+    #[serde(
+        rename = "content-type",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    content_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    subject: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    fragment: Option<Fragment>,
+}
+
+impl Extensions {
+    /// Returns the topic this operation was published to.
+    pub fn topic(&self) -> Topic {
+        Topic::from_log_id(self.log_id, self.ephemeral)
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    pub fn fragment(&self) -> Option<Fragment> {
+        self.fragment
+    }
 }
 
 impl Extension<LogId> for Extensions {
@@ -31,12 +83,37 @@ impl Extension<PruneFlag> for Extensions {
     }
 }
 
+/// Options for creating a new operation.
+#[derive(Clone, Debug, Default)]
+pub struct OperationOptions {
+    /// Remove all previous operations from the log.
+    pub prune: bool,
+
+    /// Write the operation to the ephemeral log of the topic.
+    pub ephemeral: bool,
+
+    /// Number of seconds after which the operation should not be delivered anymore.
+    pub ttl: Option<u64>,
+
+    /// Content type of the payload, for example a MIME type.
+    pub content_type: Option<String>,
+
+    /// Address of the payload within the topic, for example the original MQTT topic.
+    pub subject: Option<String>,
+
+    /// Proof-of-work stamp, see [`mine_pow_nonce`].
+    pub pow_nonce: Option<u64>,
+
+    /// Position of the operation when the payload was split into multiple operations.
+    pub fragment: Option<Fragment>,
+}
+
 pub async fn create_operation(
     store: &mut MemoryStore<LogId, Extensions>,
     log_id: LogId,
     private_key: &PrivateKey,
     body: Option<&[u8]>,
-    prune_flag: bool,
+    options: OperationOptions,
 ) -> (Header<Extensions>, Option<Body>) {
     let body = body.map(Body::new);
     let public_key = private_key.public_key();
@@ -48,14 +125,19 @@ pub async fn create_operation(
         None => (0, None),
     };
 
-    let timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("time from operation system")
-        .as_secs();
+    let timestamp = now();
+
+    let payload_hash = body.as_ref().map(|body| body.hash());
 
     let extensions = Extensions {
         log_id,
-        prune_flag: PruneFlag::new(prune_flag),
+        prune_flag: PruneFlag::new(options.prune),
+        pow_nonce: options.pow_nonce,
+        ephemeral: options.ephemeral,
+        ttl: options.ttl,
+        content_type: options.content_type,
+        subject: options.subject,
+        fragment: options.fragment,
     };
 
     let mut header = Header {
@@ -63,7 +145,7 @@ pub async fn create_operation(
         public_key,
         signature: None,
         payload_size: body.as_ref().map_or(0, |body| body.size()),
-        payload_hash: body.as_ref().map(|body| body.hash()),
+        payload_hash,
         timestamp,
         seq_num,
         backlink,
@@ -75,6 +157,16 @@ pub async fn create_operation(
     (header, body)
 }
 
+/// Returns the sequence number of the next operation we create in the log.
+pub async fn next_seq_num(
+    store: &MemoryStore<LogId, Extensions>,
+    public_key: &PublicKey,
+    log_id: &LogId,
+) -> u64 {
+    let Ok(latest_operation) = store.latest_operation(public_key, log_id).await;
+    latest_operation.map_or(0, |(header, _)| header.seq_num + 1)
+}
+
 pub fn encode_gossip_message(
     header: &Header<Extensions>,
     body: Option<&Body>,
@@ -85,3 +177,353 @@ pub fn encode_gossip_message(
 pub fn decode_gossip_message(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), DecodeError> {
     decode_cbor(bytes)
 }
+
+pub fn decode_header(bytes: &[u8]) -> Result<Header<Extensions>, DecodeError> {
+    decode_cbor(bytes)
+}
+
+/// Rejects operations with an invalid signature, a payload not matching the header or without
+/// extensions.
+pub fn check_operation(header: &Header<Extensions>, body: Option<&Body>) -> Result<()> {
+    validate_header(header)?;
+
+    if let Some(body) = body {
+        if header.payload_hash != Some(body.hash()) || header.payload_size != body.size() {
+            bail!("payload does not match header");
+        }
+    }
+
+    if header.extensions.is_none() {
+        bail!("missing extensions in header");
+    }
+
+    Ok(())
+}
+
+/// Rejects operations which claim to be created further in the future than the given drift
+/// allows, this protects us from peers with broken clocks.
+pub fn check_max_drift(header: &Header<Extensions>, max_drift: Duration) -> Result<()> {
+    let now = now();
+    if header.timestamp > now.saturating_add(max_drift.as_secs()) {
+        bail!(
+            "timestamp {} is {}s in the future",
+            header.timestamp,
+            header.timestamp - now
+        );
+    }
+    Ok(())
+}
+
+/// Rejects operations which were created longer ago than the given age, this protects us from
+/// replayed, ancient data.
+pub fn check_max_age(header: &Header<Extensions>, max_age: Duration) -> Result<()> {
+    let now = now();
+    if header.timestamp.saturating_add(max_age.as_secs()) < now {
+        bail!(
+            "timestamp {} is {}s in the past",
+            header.timestamp,
+            now - header.timestamp
+        );
+    }
+    Ok(())
+}
+
+/// Returns true if the operation's time-to-live has passed.
+pub fn is_expired(header: &Header<Extensions>) -> bool {
+    let ttl = header
+        .extensions
+        .as_ref()
+        .and_then(|extensions| extensions.ttl);
+    match ttl {
+        Some(ttl) => header.timestamp.saturating_add(ttl) < now(),
+        None => false,
+    }
+}
+
+/// Highest proof-of-work difficulty we allow, higher ones would keep publishing busy forever.
+///
+/// Stamps of this difficulty take around a second to mine, so pending messages are still
+/// published within the shutdown deadline.
+pub const MAX_POW_DIFFICULTY: u8 = 20;
+
+/// Rejects operations which do not carry a valid proof-of-work stamp with at least the given
+/// difficulty, this makes it expensive for peers to spam open topics.
+pub fn check_proof_of_work(header: &Header<Extensions>, difficulty: u8) -> Result<()> {
+    let Some(extensions) = header.extensions.as_ref() else {
+        bail!("missing extensions");
+    };
+
+    let Some(nonce) = extensions.pow_nonce else {
+        bail!("missing proof-of-work stamp");
+    };
+
+    let hash = pow_hash(
+        &header.public_key,
+        &extensions.log_id,
+        header.seq_num,
+        header.payload_hash,
+        nonce,
+    );
+    if leading_zero_bits(hash.as_bytes()) < difficulty as u32 {
+        bail!("proof-of-work stamp does not meet difficulty {difficulty}");
+    }
+
+    Ok(())
+}
+
+/// Mines a proof-of-work stamp for the operation at the given position in the log.
+///
+/// This can take a long time with high difficulties, so it runs on a blocking thread. Mining stops
+/// when the returned future is dropped.
+pub async fn mine_pow_nonce(
+    public_key: PublicKey,
+    log_id: LogId,
+    seq_num: u64,
+    payload: &[u8],
+    difficulty: u8,
+) -> u64 {
+    let payload_hash = Body::new(payload).hash();
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancelled.clone());
+
+    task::spawn_blocking(move || {
+        find_pow_nonce(
+            &public_key,
+            &log_id,
+            seq_num,
+            Some(payload_hash),
+            difficulty,
+            &cancelled,
+        )
+    })
+    .await
+    .expect("proof-of-work task does not panic")
+    .expect("mining is only cancelled when nobody waits for it")
+}
+
+/// Sets the flag when dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Searches for a nonce which, hashed together with the operation's author, log, position and
+/// payload, results in a hash with at least `difficulty` leading zero bits.
+fn find_pow_nonce(
+    public_key: &PublicKey,
+    log_id: &LogId,
+    seq_num: u64,
+    payload_hash: Option<Hash>,
+    difficulty: u8,
+    cancelled: &AtomicBool,
+) -> Option<u64> {
+    for nonce in 0.. {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let hash = pow_hash(public_key, log_id, seq_num, payload_hash, nonce);
+        if leading_zero_bits(hash.as_bytes()) >= difficulty as u32 {
+            return Some(nonce);
+        }
+    }
+    unreachable!("nonce space is large enough")
+}
+
+fn pow_hash(
+    public_key: &PublicKey,
+    log_id: &LogId,
+    seq_num: u64,
+    payload_hash: Option<Hash>,
+    nonce: u64,
+) -> Hash {
+    let mut buf = Vec::with_capacity(32 * 3 + 8 * 2);
+    buf.extend_from_slice(public_key.as_bytes());
+    buf.extend_from_slice(log_id);
+    buf.extend_from_slice(&seq_num.to_be_bytes());
+    if let Some(payload_hash) = payload_hash {
+        buf.extend_from_slice(payload_hash.as_bytes());
+    }
+    buf.extend_from_slice(&nonce.to_be_bytes());
+    Hash::new(buf)
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in bytes {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time from operation system")
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    use p2panda_core::{Body, Header, PrivateKey};
+    use p2panda_store::MemoryStore;
+    use p2panda_stream::operation::ingest_operation;
+
+    use crate::topic::LogId;
+
+    use super::{
+        check_max_age, check_max_drift, check_operation, check_proof_of_work, create_operation,
+        find_pow_nonce, mine_pow_nonce, next_seq_num, now, Extensions, OperationOptions,
+    };
+
+    fn header(timestamp: u64) -> Header<Extensions> {
+        Header {
+            version: 1,
+            public_key: PrivateKey::new().public_key(),
+            signature: None,
+            payload_size: 0,
+            payload_hash: None,
+            timestamp,
+            seq_num: 0,
+            backlink: None,
+            previous: vec![],
+            extensions: None,
+        }
+    }
+
+    const LOG_ID: LogId = [0; 32];
+
+    async fn create_stamped(
+        store: &mut MemoryStore<LogId, Extensions>,
+        private_key: &PrivateKey,
+        nonce: u64,
+    ) -> (Header<Extensions>, Option<Body>) {
+        create_operation(
+            store,
+            LOG_ID,
+            private_key,
+            Some(b"hello"),
+            OperationOptions {
+                pow_nonce: Some(nonce),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    #[test]
+    fn max_drift() {
+        let max_drift = Duration::from_secs(60);
+
+        assert!(check_max_drift(&header(0), max_drift).is_ok());
+        assert!(check_max_drift(&header(now()), max_drift).is_ok());
+        assert!(check_max_drift(&header(now() + 3600), max_drift).is_err());
+        assert!(check_max_drift(&header(u64::MAX), max_drift).is_err());
+
+        // Large drifts don't overflow.
+        let max_drift = Duration::from_secs(u64::MAX);
+        assert!(check_max_drift(&header(0), max_drift).is_ok());
+        assert!(check_max_drift(&header(u64::MAX), max_drift).is_ok());
+    }
+
+    #[test]
+    fn max_age() {
+        let max_age = Duration::from_secs(60);
+
+        assert!(check_max_age(&header(0), max_age).is_err());
+        assert!(check_max_age(&header(now() - 3600), max_age).is_err());
+        assert!(check_max_age(&header(now()), max_age).is_ok());
+        assert!(check_max_age(&header(u64::MAX), max_age).is_ok());
+
+        // Large ages don't overflow.
+        let max_age = Duration::from_secs(u64::MAX);
+        assert!(check_max_age(&header(0), max_age).is_ok());
+        assert!(check_max_age(&header(u64::MAX), max_age).is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_signature_and_payload() {
+        assert!(check_operation(&header(now()), None).is_err());
+
+        let private_key = PrivateKey::new();
+        let mut store = MemoryStore::new();
+        let (header, body) = create_operation(
+            &mut store,
+            [0; 32],
+            &private_key,
+            Some(b"hello"),
+            OperationOptions::default(),
+        )
+        .await;
+        assert!(check_operation(&header, body.as_ref()).is_ok());
+        assert!(check_operation(&header, None).is_ok());
+
+        let other_body = Body::new(b"other");
+        assert!(check_operation(&header, Some(&other_body)).is_err());
+
+        let mut forged = header.clone();
+        forged.public_key = PrivateKey::new().public_key();
+        assert!(check_operation(&forged, body.as_ref()).is_err());
+    }
+
+    #[tokio::test]
+    async fn mine_stamps_for_log_position() {
+        // Stamps don't depend on the timestamp, so mining them with a fixed key is deterministic.
+        let private_key = PrivateKey::from_bytes(&[1; 32]);
+        let public_key = private_key.public_key();
+        let mut store = MemoryStore::new();
+        let log_id = LOG_ID;
+
+        assert_eq!(next_seq_num(&store, &public_key, &log_id).await, 0);
+        let first_nonce = mine_pow_nonce(public_key, log_id, 0, b"hello", 16).await;
+        let (first, body) = create_stamped(&mut store, &private_key, first_nonce).await;
+        assert!(check_proof_of_work(&first, 16).is_ok());
+
+        let header_bytes = first.to_bytes();
+        ingest_operation(
+            &mut store,
+            first.clone(),
+            body,
+            header_bytes,
+            &log_id,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(next_seq_num(&store, &public_key, &log_id).await, 1);
+        let second_nonce = mine_pow_nonce(public_key, log_id, 1, b"hello", 16).await;
+        assert_ne!(first_nonce, second_nonce);
+        let (second, _) = create_stamped(&mut store, &private_key, second_nonce).await;
+        assert_eq!(second.seq_num, 1);
+        assert_eq!(second.backlink, Some(first.hash()));
+        assert!(check_proof_of_work(&second, 16).is_ok());
+
+        // Stamps mined for another position in the log are not valid.
+        let (reused, _) = create_stamped(&mut store, &private_key, first_nonce).await;
+        assert_eq!(reused.seq_num, 1);
+        assert!(check_proof_of_work(&reused, 16).is_err());
+
+        let (moved, _) = create_stamped(&mut MemoryStore::new(), &private_key, second_nonce).await;
+        assert_eq!(moved.seq_num, 0);
+        assert!(check_proof_of_work(&moved, 16).is_err());
+    }
+
+    #[test]
+    fn stop_cancelled_mining() {
+        let public_key = PrivateKey::new().public_key();
+        let cancelled = AtomicBool::new(true);
+        assert_eq!(
+            find_pow_nonce(&public_key, &[0; 32], 0, None, 255, &cancelled),
+            None
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use rosc::address::{Matcher, OscAddress};
+use rosc::{decoder, encoder, OscPacket};
+
+/// OSC address pattern, for example "/scene/*".
+#[derive(Clone, Debug)]
+pub struct AddressPattern(Matcher);
+
+impl AddressPattern {
+    fn matches(&self, address: &OscAddress) -> bool {
+        self.0.match_address(address)
+    }
+}
+
+impl FromStr for AddressPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let matcher = Matcher::new(value).map_err(|err| anyhow!("{err}"))?;
+        Ok(Self(matcher))
+    }
+}
+
+/// Sends OSC messages received from the network matching the pattern to another address.
+#[derive(Clone, Debug)]
+pub struct OscRoute {
+    pub pattern: AddressPattern,
+    pub addr: SocketAddr,
+}
+
+impl FromStr for OscRoute {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, addr)) = value.split_once('=') else {
+            bail!("route needs to be in the form of <PATTERN>=<ADDR:PORT>");
+        };
+
+        Ok(Self {
+            pattern: pattern.parse()?,
+            addr: addr.parse()?,
+        })
+    }
+}
+
+/// OSC packet received from the network, split by routes.
+#[derive(Debug, Default)]
+pub struct Routed {
+    /// Packets for the addresses of all matching routes.
+    pub routes: Vec<(SocketAddr, Vec<u8>)>,
+
+    /// Packet with all remaining messages for the UDP clients.
+    pub remaining: Option<Vec<u8>>,
+}
+
+/// Validates, filters and routes Open Sound Control packets by their address.
+#[derive(Clone, Debug, Default)]
+pub struct OscConfig {
+    /// Only publish OSC messages matching any of these patterns, all messages are published if
+    /// none are given.
+    pub publish: Vec<AddressPattern>,
+
+    /// Routes for OSC messages received from the network, the first matching route is used.
+    /// Messages without any matching route are sent to the UDP clients.
+    pub routes: Vec<OscRoute>,
+}
+
+impl OscConfig {
+    /// Returns the OSC packet with all messages we want to publish, or nothing if no message of
+    /// the packet matches.
+    ///
+    /// Fails if the datagram is not a valid OSC packet.
+    pub fn filter(&self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        let packet = decode(bytes)?;
+
+        if self.publish.is_empty() {
+            return Ok(Some(bytes.to_vec()));
+        }
+
+        match retain(packet, &|address| {
+            self.publish.iter().any(|pattern| pattern.matches(address))
+        }) {
+            Some(packet) => Ok(Some(encoder::encode(&packet)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Splits an OSC packet received from the network by the configured routes.
+    ///
+    /// Fails if the payload is not a valid OSC packet.
+    pub fn route(&self, bytes: &[u8]) -> Result<Routed> {
+        let packet = decode(bytes)?;
+
+        if self.routes.is_empty() {
+            return Ok(Routed {
+                routes: Vec::new(),
+                remaining: Some(bytes.to_vec()),
+            });
+        }
+
+        let route_index = |address: &OscAddress| {
+            self.routes
+                .iter()
+                .position(|route| route.pattern.matches(address))
+        };
+
+        let mut routes = Vec::new();
+        for (index, route) in self.routes.iter().enumerate() {
+            if let Some(packet) = retain(packet.clone(), &|address| {
+                route_index(address) == Some(index)
+            }) {
+                routes.push((route.addr, encoder::encode(&packet)?));
+            }
+        }
+
+        let remaining = match retain(packet, &|address| route_index(address).is_none()) {
+            Some(packet) => Some(encoder::encode(&packet)?),
+            None => None,
+        };
+
+        Ok(Routed { routes, remaining })
+    }
+}
+
+/// Decodes and validates an OSC packet, including the addresses of all its messages.
+fn decode(bytes: &[u8]) -> Result<OscPacket> {
+    let (remainder, packet) = decoder::decode_udp(bytes)?;
+    if !remainder.is_empty() {
+        bail!("unexpected {} bytes after osc packet", remainder.len());
+    }
+    validate(&packet)?;
+    Ok(packet)
+}
+
+fn validate(packet: &OscPacket) -> Result<()> {
+    match packet {
+        OscPacket::Message(message) => {
+            OscAddress::new(message.addr.clone())?;
+        }
+        OscPacket::Bundle(bundle) => {
+            for packet in &bundle.content {
+                validate(packet)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes all messages not matching the filter from the packet, bundles without any messages are
+/// removed as well.
+fn retain(packet: OscPacket, filter: &impl Fn(&OscAddress) -> bool) -> Option<OscPacket> {
+    match packet {
+        OscPacket::Message(message) => {
+            let address = OscAddress::new(message.addr.clone()).ok()?;
+            filter(&address).then_some(OscPacket::Message(message))
+        }
+        OscPacket::Bundle(mut bundle) => {
+            bundle.content = bundle
+                .content
+                .into_iter()
+                .filter_map(|packet| retain(packet, filter))
+                .collect();
+            (!bundle.content.is_empty()).then_some(OscPacket::Bundle(bundle))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rosc::{decoder, encoder, OscBundle, OscMessage, OscPacket, OscTime, OscType};
+
+    use super::{OscConfig, OscRoute};
+
+    fn message(addr: &str) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_owned(),
+            args: vec![OscType::Int(1)],
+        })
+    }
+
+    fn bundle(content: Vec<OscPacket>) -> OscPacket {
+        OscPacket::Bundle(OscBundle {
+            timetag: OscTime {
+                seconds: 0,
+                fractional: 1,
+            },
+            content,
+        })
+    }
+
+    fn encode(packet: &OscPacket) -> Vec<u8> {
+        encoder::encode(packet).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> OscPacket {
+        decoder::decode_udp(bytes).unwrap().1
+    }
+
+    fn addr(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    fn config(publish: &[&str], routes: &[&str]) -> OscConfig {
+        OscConfig {
+            publish: publish
+                .iter()
+                .map(|pattern| pattern.parse().unwrap())
+                .collect(),
+            routes: routes.iter().map(|route| route.parse().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn filter_messages() {
+        let osc = config(&["/scene/*", "/tempo"], &[]);
+
+        let bytes = encode(&message("/scene/1"));
+        assert_eq!(osc.filter(&bytes).unwrap(), Some(bytes));
+        let bytes = encode(&message("/tempo"));
+        assert_eq!(osc.filter(&bytes).unwrap(), Some(bytes));
+
+        assert_eq!(osc.filter(&encode(&message("/scene"))).unwrap(), None);
+        assert_eq!(osc.filter(&encode(&message("/other/1"))).unwrap(), None);
+
+        // Without patterns everything is published.
+        let bytes = encode(&message("/other/1"));
+        assert_eq!(config(&[], &[]).filter(&bytes).unwrap(), Some(bytes));
+    }
+
+    #[test]
+    fn reject_invalid_packets() {
+        let osc = config(&[], &[]);
+        assert!(osc.filter(b"hello").is_err());
+        assert!(osc.route(b"hello").is_err());
+
+        // Trailing data after the packet.
+        let mut bytes = encode(&message("/scene/1"));
+        bytes.extend_from_slice(&[0; 4]);
+        assert!(osc.filter(&bytes).is_err());
+
+        // Addresses need to start with a slash.
+        assert!(osc.filter(&encode(&message("scene"))).is_err());
+        assert!(osc
+            .filter(&encode(&bundle(vec![message("scene")])))
+            .is_err());
+    }
+
+    #[test]
+    fn filter_bundles() {
+        let osc = config(&["/scene/*"], &[]);
+
+        let packet = bundle(vec![
+            message("/scene/1"),
+            message("/other"),
+            bundle(vec![message("/other"), message("/scene/2")]),
+            bundle(vec![message("/other")]),
+        ]);
+        let filtered = osc.filter(&encode(&packet)).unwrap().unwrap();
+        assert_eq!(
+            decode(&filtered),
+            bundle(vec![message("/scene/1"), bundle(vec![message("/scene/2")]),])
+        );
+    }
+
+    #[test]
+    fn drop_empty_bundles() {
+        let osc = config(&["/scene/*"], &[]);
+
+        let packet = bundle(vec![message("/other"), bundle(vec![message("/other")])]);
+        assert_eq!(osc.filter(&encode(&packet)).unwrap(), None);
+        assert_eq!(osc.filter(&encode(&bundle(Vec::new()))).unwrap(), None);
+    }
+
+    #[test]
+    fn route_messages() {
+        let osc = config(
+            &[],
+            &["/lights/*=127.0.0.1:9000", "/*/dimmer=127.0.0.1:9001"],
+        );
+
+        // The first matching route is used, everything else goes to the UDP clients.
+        let packet = bundle(vec![
+            message("/lights/dimmer"),
+            message("/sound/dimmer"),
+            message("/tempo"),
+        ]);
+        let routed = osc.route(&encode(&packet)).unwrap();
+        assert_eq!(routed.routes.len(), 2);
+        assert_eq!(routed.routes[0].0, addr("127.0.0.1:9000"));
+        assert_eq!(
+            decode(&routed.routes[0].1),
+            bundle(vec![message("/lights/dimmer")])
+        );
+        assert_eq!(routed.routes[1].0, addr("127.0.0.1:9001"));
+        assert_eq!(
+            decode(&routed.routes[1].1),
+            bundle(vec![message("/sound/dimmer")])
+        );
+        assert_eq!(
+            decode(&routed.remaining.unwrap()),
+            bundle(vec![message("/tempo")])
+        );
+
+        let bytes = encode(&message("/lights/1"));
+        let routed = osc.route(&bytes).unwrap();
+        assert_eq!(routed.routes, vec![(addr("127.0.0.1:9000"), bytes.clone())]);
+        assert_eq!(routed.remaining, None);
+
+        // Without routes everything goes to the UDP clients.
+        let routed = config(&[], &[]).route(&bytes).unwrap();
+        assert!(routed.routes.is_empty());
+        assert_eq!(routed.remaining, Some(bytes));
+    }
+
+    #[test]
+    fn parse_routes() {
+        let route: OscRoute = "/lights/*=127.0.0.1:9000".parse().unwrap();
+        assert_eq!(route.addr, addr("127.0.0.1:9000"));
+
+        for value in [
+            "/lights/*",
+            "/lights/*=",
+            "/lights/*=localhost",
+            "=127.0.0.1:9000",
+            "lights=127.0.0.1:9000",
+            "/lights/[=127.0.0.1:9000",
+        ] {
+            assert!(value.parse::<OscRoute>().is_err(), "{value}");
+        }
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadBuf,
+};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::bridge::{decode_input, Bridge};
+use crate::message::{EnvelopeFormat, Message, OutgoingMessage};
+use crate::tasks::TaskGroup;
+use crate::tcp::{read_frame, write_frame, MAX_FRAME_SIZE};
+use crate::topic::Topic;
+
+/// How messages are separated from each other on stdin and stdout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Every line is one message, the newline character is not part of it.
+    #[default]
+    Lines,
+
+    /// Every message is prefixed with its length as an unsigned 32-bit big-endian integer, this is
+    /// safe for binary data.
+    LengthPrefixed,
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            Framing::Lines => "lines",
+            Framing::LengthPrefixed => "length-prefixed",
+        };
+        write!(f, "{value}")
+    }
+}
+
+impl FromStr for Framing {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "lines" => Ok(Self::Lines),
+            "length-prefixed" => Ok(Self::LengthPrefixed),
+            _ => bail!("unknown framing \"{value}\", possible values are: lines, length-prefixed"),
+        }
+    }
+}
+
+/// Publishes every message read from stdin and writes every message received from the network to
+/// stdout.
+#[derive(Debug)]
+pub struct PipeBridge {
+    stdin_rx: mpsc::Receiver<OutgoingMessage>,
+    stdin_closed: watch::Sender<Option<HashSet<Topic>>>,
+    topics: HashSet<Topic>,
+    default_topic: Topic,
+    stdout: io::Stdout,
+    framing: Framing,
+    envelope: Option<EnvelopeFormat>,
+}
+
+impl PipeBridge {
+    /// Reads messages from the given input, usually stdin.
+    ///
+    /// As soon as the input was closed and all messages read from it were received, the topics
+    /// they were published to are sent to the watch channel.
+    ///
+    /// Blocking reads can't be cancelled, so the input is read on its own thread which is left
+    /// behind when the node shuts down. Messages are decoded in a task of the given group.
+    pub fn new(
+        stdin: impl Read + Send + 'static,
+        framing: Framing,
+        input: Option<EnvelopeFormat>,
+        envelope: Option<EnvelopeFormat>,
+        default_topic: Topic,
+        stdin_closed: watch::Sender<Option<HashSet<Topic>>>,
+        tasks: &TaskGroup,
+    ) -> Self {
+        let (chunks_tx, chunks_rx) = mpsc::channel(16);
+        thread::spawn(move || read_chunks(stdin, chunks_tx));
+
+        // Reading from stdin is not cancel safe, so we're doing it in a separate task.
+        let (stdin_tx, stdin_rx) = mpsc::channel(16);
+        tasks.spawn_graceful("pipe-stdin", |shutdown| async move {
+            let stdin = ChunkReader::new(chunks_rx);
+            tokio::select! {
+                result = read_stdin(stdin, stdin_tx, framing, input) => result,
+                _ = shutdown.cancelled() => Ok(()),
+            }
+        });
+
+        Self {
+            stdin_rx,
+            stdin_closed,
+            topics: HashSet::new(),
+            default_topic,
+            stdout: io::stdout(),
+            framing,
+            envelope,
+        }
+    }
+}
+
+#[async_trait]
+impl Bridge for PipeBridge {
+    fn name(&self) -> &'static str {
+        "pipe"
+    }
+
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>> {
+        match self.stdin_rx.recv().await {
+            Some(message) => {
+                let topic = message.topic.as_ref().unwrap_or(&self.default_topic);
+                self.topics.insert(topic.clone());
+                Ok(Some(message))
+            }
+            // Keep writing received messages to stdout after stdin was closed.
+            None => {
+                // Receiving is cancelled and started again whenever a message is delivered.
+                let topics = &mut self.topics;
+                self.stdin_closed.send_if_modified(|closed| {
+                    if closed.is_some() {
+                        return false;
+                    }
+                    *closed = Some(std::mem::take(topics));
+                    true
+                });
+                std::future::pending().await
+            }
+        }
+    }
+
+    async fn deliver(&mut self, message: &Message) -> Result<()> {
+        let bytes = message.to_bytes(self.envelope)?;
+
+        match self.framing {
+            Framing::Lines => {
+                self.stdout.write_all(&bytes).await?;
+                self.stdout.write_all(b"\n").await?;
+            }
+            Framing::LengthPrefixed => write_frame(&mut self.stdout, &bytes).await?,
+        }
+
+        self.stdout.flush().await?;
+
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        // Stops the task reading from stdin, it might still wait for a message to be received.
+        self.stdin_rx.close();
+        Ok(())
+    }
+}
+
+/// Waits until we joined the gossip overlays of all topics, then for the given duration.
+pub async fn linger(
+    mut gossip_joined: watch::Receiver<HashSet<Topic>>,
+    topics: &HashSet<Topic>,
+    duration: Duration,
+) {
+    if !topics.is_subset(&gossip_joined.borrow()) {
+        debug!("wait for gossip overlays of published topics");
+    }
+    // The publisher only stops when the node shuts down.
+    let _ = gossip_joined
+        .wait_for(|joined| topics.is_subset(joined))
+        .await;
+    time::sleep(duration).await;
+}
+
+/// Reads from the blocking input until it is closed or nobody receives the chunks anymore.
+fn read_chunks(mut stdin: impl Read, chunks_tx: mpsc::Sender<std::io::Result<Vec<u8>>>) {
+    let mut buf = vec![0; 8192];
+
+    loop {
+        let chunk = match stdin.read(&mut buf) {
+            Ok(0) => {
+                debug!("stdin closed");
+                return;
+            }
+            Ok(len) => Ok(buf[..len].to_vec()),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+
+        let failed = chunk.is_err();
+        if chunks_tx.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Asynchronous reader over the chunks read on another thread.
+#[derive(Debug)]
+struct ChunkReader {
+    chunks_rx: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn new(chunks_rx: mpsc::Receiver<std::io::Result<Vec<u8>>>) -> Self {
+        Self {
+            chunks_rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChunkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.pos == self.chunk.len() {
+            match ready!(self.chunks_rx.poll_recv(cx)) {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The input was closed.
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(self.chunk.len() - self.pos);
+        buf.put_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn read_stdin(
+    stdin: impl AsyncRead + Unpin,
+    stdin_tx: mpsc::Sender<OutgoingMessage>,
+    framing: Framing,
+    input: Option<EnvelopeFormat>,
+) -> Result<()> {
+    let mut stdin = BufReader::new(stdin);
+
+    loop {
+        let buf = match framing {
+            Framing::Lines => {
+                let Some(mut line) = read_line(&mut stdin, MAX_FRAME_SIZE).await? else {
+                    return Ok(());
+                };
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+                if line.is_empty() {
+                    continue;
+                }
+                line
+            }
+            Framing::LengthPrefixed => match read_frame(&mut stdin, MAX_FRAME_SIZE).await? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            },
+        };
+
+        let message = match decode_input(input, &buf) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("invalid message: {err}");
+                continue;
+            }
+        };
+
+        // The bridge stopped.
+        if stdin_tx.send(message).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the next line without its newline character, returns nothing when the reader was closed.
+///
+/// Lines longer than the given size are skipped, so we never buffer more than that.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let mut oversized = false;
+
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if oversized {
+                warn!(max_len, "skip line exceeding max. size");
+            }
+            return Ok((!line.is_empty()).then_some(line));
+        }
+
+        let (chunk, consumed, complete) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(pos) => (&available[..pos], pos + 1, true),
+            None => (available, available.len(), false),
+        };
+
+        if line.len() + chunk.len() > max_len {
+            oversized = true;
+            line.clear();
+        } else if !oversized {
+            line.extend_from_slice(chunk);
+        }
+        reader.consume(consumed);
+
+        if complete {
+            if oversized {
+                warn!(max_len, "skip line exceeding max. size");
+                oversized = false;
+                continue;
+            }
+            return Ok(Some(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Cursor;
+    use std::pin::pin;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tokio::sync::{broadcast, watch};
+    use tokio::time::{timeout, Instant};
+
+    use crate::bridge::Bridge;
+    use crate::tasks::TaskGroup;
+    use crate::topic::Topic;
+
+    use super::{linger, read_line, Framing, PipeBridge};
+
+    #[tokio::test]
+    async fn read_lines() {
+        let mut reader: &[u8] = b"hello\n\nworld";
+        assert_eq!(
+            read_line(&mut reader, 5).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_line(&mut reader, 5).await.unwrap(), Some(Vec::new()));
+        assert_eq!(
+            read_line(&mut reader, 5).await.unwrap(),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(read_line(&mut reader, 5).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn skip_oversized_lines() {
+        let mut reader: &[u8] = b"too long\nok\ntoo long";
+        assert_eq!(
+            read_line(&mut reader, 5).await.unwrap(),
+            Some(b"ok".to_vec())
+        );
+        assert_eq!(read_line(&mut reader, 5).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn hand_over_last_lines_before_closing() {
+        let topic = Topic::from_str("pipe").unwrap();
+        let tasks = TaskGroup::new(broadcast::channel(16).0);
+        let (stdin_closed_tx, mut stdin_closed_rx) = watch::channel(None);
+        let mut bridge = PipeBridge::new(
+            Cursor::new(b"one\n\ntwo\r\nthree".to_vec()),
+            Framing::Lines,
+            None,
+            None,
+            topic.clone(),
+            stdin_closed_tx,
+            &tasks,
+        );
+
+        // Stdin is only reported as closed after every line was received.
+        for payload in [b"one".as_slice(), b"two", b"three"] {
+            let message = bridge.recv().await.unwrap().unwrap();
+            assert_eq!(message.payload, payload);
+            assert_eq!(*stdin_closed_rx.borrow(), None);
+        }
+
+        // Receiving pends afterwards, so messages from the network are still written to stdout.
+        assert!(timeout(Duration::from_millis(100), bridge.recv())
+            .await
+            .is_err());
+        let topics = stdin_closed_rx
+            .wait_for(Option::is_some)
+            .await
+            .unwrap()
+            .clone();
+        assert_eq!(topics, Some(HashSet::from([topic])));
+
+        // Receiving again doesn't send the topics twice.
+        assert!(timeout(Duration::from_millis(100), bridge.recv())
+            .await
+            .is_err());
+        assert!(!stdin_closed_rx.has_changed().unwrap());
+
+        bridge.close().await.unwrap();
+        assert!(tasks.shutdown().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn linger_after_joining_topics() {
+        let topics = HashSet::from([Topic::from_str("pipe").unwrap()]);
+        let (gossip_joined_tx, gossip_joined_rx) = watch::channel(HashSet::new());
+        let mut linger = pin!(linger(gossip_joined_rx, &topics, Duration::from_secs(5)));
+
+        // We didn't join the topic yet.
+        assert!(timeout(Duration::from_secs(60), &mut linger).await.is_err());
+
+        gossip_joined_tx.send_replace(topics.clone());
+        let joined = Instant::now();
+        linger.await;
+        assert_eq!(joined.elapsed(), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,563 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use p2panda_core::PublicKey;
+use tracing::{debug, warn};
+
+use crate::topic::Topic;
+
+/// Maximum number of datagrams we hold back when the "queue" policy is used.
+const MAX_QUEUE_LEN: usize = 1024;
+
+/// How often we look for authors we don't need to keep track of anymore.
+const AUTHOR_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Simple token bucket which refills continuously with the given rate per second.
+///
+/// The bucket can hold up to `burst` tokens, which allows short bursts as long as the average rate
+/// stays below the limit.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = self.tokens_at(now);
+        self.last_refill = now;
+    }
+
+    /// Returns the number of tokens the bucket holds at the given point in time.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /// Returns true if the bucket is full again at the given point in time, it is then not
+    /// different from a new one.
+    fn is_full_at(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.burst
+    }
+
+    /// Returns the point in time when the given amount of tokens will be available.
+    fn ready_at(&self, amount: f64) -> Instant {
+        let missing = (amount - self.tokens).max(0.0);
+        self.last_refill + Duration::from_secs_f64(missing / self.rate)
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+#[derive(Debug)]
+struct AuthorLimit {
+    operations: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    dropped: u64,
+}
+
+impl AuthorLimit {
+    fn is_idle_at(&self, now: Instant) -> bool {
+        self.operations
+            .as_ref()
+            .is_none_or(|bucket| bucket.is_full_at(now))
+            && self
+                .bytes
+                .as_ref()
+                .is_none_or(|bucket| bucket.is_full_at(now))
+    }
+}
+
+/// Limits the number of operations and bytes per second we accept from every author.
+///
+/// Operations exceeding the limit are not delivered to local applications. The number of dropped
+/// operations is counted per author and reported as soon as the author is within the limits
+/// again.
+///
+/// The limits are checked after operations were ingested and fragmented payloads were
+/// reassembled, so they are still stored and synced. Dropping them before would leave gaps in the
+/// author's log, which stall all of their later operations.
+#[derive(Debug)]
+pub struct AuthorRateLimiter {
+    max_operations: Option<u32>,
+    max_bytes: Option<u32>,
+    authors: HashMap<PublicKey, AuthorLimit>,
+    last_eviction: Instant,
+}
+
+impl AuthorRateLimiter {
+    /// Limits need to be larger than zero, leave them out for no limit.
+    pub fn new(max_operations: Option<u32>, max_bytes: Option<u32>) -> Self {
+        debug_assert!(max_operations != Some(0) && max_bytes != Some(0));
+        Self {
+            max_operations,
+            max_bytes,
+            authors: HashMap::new(),
+            last_eviction: Instant::now(),
+        }
+    }
+
+    /// Returns true if a message of the given size by this author is within the limits.
+    ///
+    /// Payloads larger than the bytes limit are accepted when the author didn't send anything else
+    /// for a second, otherwise they could never be delivered.
+    pub fn check(&mut self, public_key: &PublicKey, len: usize) -> bool {
+        if self.max_operations.is_none() && self.max_bytes.is_none() {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_eviction) >= AUTHOR_EVICTION_INTERVAL {
+            self.evict_idle(now);
+        }
+
+        let limit = self
+            .authors
+            .entry(*public_key)
+            .or_insert_with(|| AuthorLimit {
+                operations: self.max_operations.map(|rate| TokenBucket::new(rate, rate)),
+                bytes: self.max_bytes.map(|rate| TokenBucket::new(rate, rate)),
+                dropped: 0,
+            });
+
+        if let Some(bucket) = limit.operations.as_mut() {
+            bucket.refill();
+        }
+
+        if let Some(bucket) = limit.bytes.as_mut() {
+            bucket.refill();
+        }
+
+        let bytes = limit
+            .bytes
+            .as_ref()
+            .map_or(0.0, |bucket| (len as f64).min(bucket.burst));
+
+        let allowed = limit
+            .operations
+            .as_ref()
+            .is_none_or(|bucket| bucket.has(1.0))
+            && limit.bytes.as_ref().is_none_or(|bucket| bucket.has(bytes));
+
+        if !allowed {
+            limit.dropped += 1;
+            debug!(
+                public_key = %public_key,
+                dropped = limit.dropped,
+                "author exceeded rate limit, drop operation"
+            );
+            return false;
+        }
+
+        if let Some(bucket) = limit.operations.as_mut() {
+            bucket.take(1.0);
+        }
+
+        if let Some(bucket) = limit.bytes.as_mut() {
+            bucket.take(bytes);
+        }
+
+        if limit.dropped > 0 {
+            warn!(
+                public_key = %public_key,
+                dropped = limit.dropped,
+                "dropped operations from author exceeding rate limit"
+            );
+            limit.dropped = 0;
+        }
+
+        true
+    }
+
+    /// Forgets all authors whose buckets are full again, they would start with full buckets
+    /// anyways.
+    fn evict_idle(&mut self, now: Instant) {
+        self.authors.retain(|public_key, limit| {
+            if !limit.is_idle_at(now) {
+                return true;
+            }
+
+            if limit.dropped > 0 {
+                warn!(
+                    public_key = %public_key,
+                    dropped = limit.dropped,
+                    "dropped operations from author exceeding rate limit"
+                );
+            }
+
+            false
+        });
+        self.last_eviction = now;
+    }
+}
+
+/// What to do with datagrams from the local application which exceed the publish rate limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Discard datagrams exceeding the limit.
+    #[default]
+    Drop,
+
+    /// Hold back datagrams exceeding the limit and publish them in order as soon as possible.
+    Queue,
+
+    /// Only keep the latest datagram exceeding the limit and publish it as soon as possible.
+    CoalesceLatest,
+}
+
+impl fmt::Display for LimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match self {
+            LimitPolicy::Drop => "drop",
+            LimitPolicy::Queue => "queue",
+            LimitPolicy::CoalesceLatest => "coalesce-latest",
+        };
+        write!(f, "{value}")
+    }
+}
+
+impl FromStr for LimitPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "queue" => Ok(Self::Queue),
+            "coalesce-latest" => Ok(Self::CoalesceLatest),
+            _ => bail!(
+                "unknown policy \"{value}\", possible values are: drop, queue, coalesce-latest"
+            ),
+        }
+    }
+}
+
+/// Rate limit for publishing operations on a topic.
+#[derive(Clone, Copy, Debug)]
+pub struct PublishLimit {
+    /// Number of operations we publish per second on average, needs to be larger than zero.
+    pub rate: u32,
+
+    /// Number of operations we publish at once before the rate limit applies, needs to be larger
+    /// than zero.
+    pub burst: u32,
+
+    pub policy: LimitPolicy,
+}
+
+/// Limits the rate of datagrams from the local application we turn into operations, every topic
+/// has its own limit.
+///
+/// Datagrams for topics without a limit are published right away.
+#[derive(Debug)]
+pub struct PublishLimiter<T> {
+    topics: HashMap<Topic, TopicLimiter<T>>,
+}
+
+impl<T> PublishLimiter<T> {
+    /// Panics if the rate or burst of a limit is zero.
+    pub fn new(limits: HashMap<Topic, PublishLimit>) -> Self {
+        Self {
+            topics: limits
+                .into_iter()
+                .map(|(topic, limit)| {
+                    let limiter = TopicLimiter::new(topic.clone(), limit);
+                    (topic, limiter)
+                })
+                .collect(),
+        }
+    }
+
+    /// Handles a new datagram for the topic and returns it if it can be published right away.
+    pub fn push(&mut self, topic: &Topic, payload: T) -> Option<T> {
+        match self.topics.get_mut(topic) {
+            Some(limiter) => limiter.push(payload),
+            None => Some(payload),
+        }
+    }
+
+    /// Returns the point in time when the next held back datagram of any topic can be published.
+    pub fn next_ready(&self) -> Option<Instant> {
+        self.topics
+            .values()
+            .filter_map(TopicLimiter::next_ready)
+            .min()
+    }
+
+    /// Returns the next held back datagram of any topic if it can be published now.
+    pub fn pop_ready(&mut self) -> Option<T> {
+        self.topics.values_mut().find_map(TopicLimiter::pop_ready)
+    }
+
+    /// Returns all held back datagrams regardless of the rate limits, for example when shutting
+    /// down.
+    pub fn drain(&mut self) -> Vec<T> {
+        self.topics
+            .values_mut()
+            .flat_map(TopicLimiter::drain)
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct TopicLimiter<T> {
+    topic: Topic,
+    bucket: TokenBucket,
+    policy: LimitPolicy,
+    pending: VecDeque<T>,
+    dropped: u64,
+}
+
+impl<T> TopicLimiter<T> {
+    fn new(topic: Topic, limit: PublishLimit) -> Self {
+        assert!(
+            limit.rate > 0 && limit.burst > 0,
+            "publish rate and burst of topic {topic} need to be larger than zero"
+        );
+        Self {
+            topic,
+            bucket: TokenBucket::new(limit.rate, limit.burst),
+            policy: limit.policy,
+            pending: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, payload: T) -> Option<T> {
+        self.bucket.refill();
+
+        if self.pending.is_empty() && self.bucket.has(1.0) {
+            self.bucket.take(1.0);
+            self.report_dropped();
+            return Some(payload);
+        }
+
+        match self.policy {
+            LimitPolicy::Drop => {
+                self.dropped += 1;
+            }
+            LimitPolicy::Queue => {
+                if self.pending.len() >= MAX_QUEUE_LEN {
+                    self.dropped += 1;
+                } else {
+                    self.pending.push_back(payload);
+                }
+            }
+            LimitPolicy::CoalesceLatest => {
+                if self.pending.pop_front().is_some() {
+                    self.dropped += 1;
+                }
+                self.pending.push_back(payload);
+            }
+        }
+
+        None
+    }
+
+    fn next_ready(&self) -> Option<Instant> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(self.bucket.ready_at(1.0))
+        }
+    }
+
+    fn pop_ready(&mut self) -> Option<T> {
+        self.bucket.refill();
+
+        if self.pending.is_empty() || !self.bucket.has(1.0) {
+            return None;
+        }
+
+        self.bucket.take(1.0);
+        let payload = self.pending.pop_front();
+
+        if self.pending.is_empty() {
+            self.report_dropped();
+        }
+
+        payload
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.report_dropped();
+        self.pending.drain(..).collect()
+    }
+
+    fn report_dropped(&mut self) {
+        if self.dropped > 0 {
+            warn!(
+                topic = %self.topic,
+                dropped = self.dropped,
+                "dropped messages exceeding publish rate limit"
+            );
+            self.dropped = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use p2panda_core::PrivateKey;
+
+    use crate::topic::Topic;
+
+    use super::{AuthorRateLimiter, LimitPolicy, PublishLimit, PublishLimiter};
+
+    #[test]
+    fn limit_operations_per_author() {
+        let alice = PrivateKey::new().public_key();
+        let bob = PrivateKey::new().public_key();
+        let mut limiter = AuthorRateLimiter::new(Some(2), None);
+
+        assert!(limiter.check(&alice, 10));
+        assert!(limiter.check(&alice, 10));
+        assert!(!limiter.check(&alice, 10));
+
+        // Other authors have their own limits.
+        assert!(limiter.check(&bob, 10));
+    }
+
+    #[test]
+    fn limit_bytes_per_author() {
+        let alice = PrivateKey::new().public_key();
+        let mut limiter = AuthorRateLimiter::new(None, Some(100));
+
+        assert!(limiter.check(&alice, 60));
+        assert!(!limiter.check(&alice, 60));
+        assert!(limiter.check(&alice, 40));
+    }
+
+    #[test]
+    fn accept_payloads_larger_than_bytes_limit() {
+        let alice = PrivateKey::new().public_key();
+        let mut limiter = AuthorRateLimiter::new(None, Some(100));
+
+        assert!(limiter.check(&alice, 1000));
+        assert!(!limiter.check(&alice, 1));
+    }
+
+    #[test]
+    fn no_limits() {
+        let alice = PrivateKey::new().public_key();
+        let mut limiter = AuthorRateLimiter::new(None, None);
+
+        for _ in 0..1000 {
+            assert!(limiter.check(&alice, usize::MAX));
+        }
+        assert!(limiter.authors.is_empty());
+    }
+
+    #[test]
+    fn evict_idle_authors() {
+        let alice = PrivateKey::new().public_key();
+        let mut limiter = AuthorRateLimiter::new(Some(1), None);
+
+        assert!(limiter.check(&alice, 10));
+        assert!(!limiter.check(&alice, 10));
+
+        limiter.evict_idle(Instant::now());
+        assert_eq!(limiter.authors.len(), 1);
+
+        limiter.evict_idle(Instant::now() + Duration::from_secs(2));
+        assert!(limiter.authors.is_empty());
+    }
+
+    fn publish_limiter(topic: &Topic, policy: LimitPolicy) -> PublishLimiter<u32> {
+        PublishLimiter::new(HashMap::from([(
+            topic.clone(),
+            PublishLimit {
+                rate: 1,
+                burst: 2,
+                policy,
+            },
+        )]))
+    }
+
+    #[test]
+    fn limit_publishing_per_topic() {
+        let chat = Topic::from_str("chat").unwrap();
+        let sensors = Topic::from_str("sensors").unwrap();
+        let mut limiter = publish_limiter(&chat, LimitPolicy::Drop);
+
+        assert_eq!(limiter.push(&chat, 1), Some(1));
+        assert_eq!(limiter.push(&chat, 2), Some(2));
+        assert_eq!(limiter.push(&chat, 3), None);
+
+        // Topics without a limit are not held back.
+        for value in 0..10 {
+            assert_eq!(limiter.push(&sensors, value), Some(value));
+        }
+
+        assert_eq!(limiter.next_ready(), None);
+        assert!(limiter.drain().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "need to be larger than zero")]
+    fn reject_zero_publish_rate() {
+        PublishLimiter::<u32>::new(HashMap::from([(
+            Topic::from_str("chat").unwrap(),
+            PublishLimit {
+                rate: 0,
+                burst: 2,
+                policy: LimitPolicy::Drop,
+            },
+        )]));
+    }
+
+    #[test]
+    fn queue_messages_exceeding_limit() {
+        let chat = Topic::from_str("chat").unwrap();
+        let mut limiter = publish_limiter(&chat, LimitPolicy::Queue);
+
+        assert_eq!(limiter.push(&chat, 1), Some(1));
+        assert_eq!(limiter.push(&chat, 2), Some(2));
+        assert_eq!(limiter.push(&chat, 3), None);
+        assert_eq!(limiter.push(&chat, 4), None);
+
+        assert!(limiter.next_ready().is_some());
+        assert_eq!(limiter.pop_ready(), None);
+        assert_eq!(limiter.drain(), vec![3, 4]);
+        assert_eq!(limiter.next_ready(), None);
+    }
+
+    #[test]
+    fn coalesce_messages_exceeding_limit() {
+        let chat = Topic::from_str("chat").unwrap();
+        let mut limiter = publish_limiter(&chat, LimitPolicy::CoalesceLatest);
+
+        assert_eq!(limiter.push(&chat, 1), Some(1));
+        assert_eq!(limiter.push(&chat, 2), Some(2));
+        assert_eq!(limiter.push(&chat, 3), None);
+        assert_eq!(limiter.push(&chat, 4), None);
+
+        assert_eq!(limiter.drain(), vec![4]);
+    }
+}
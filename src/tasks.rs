@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::broadcast;
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::events::Event;
+
+/// How long we wait for tasks to stop after asking them to, before we abort them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Background task which failed, panicked or didn't stop in time.
+#[derive(Debug)]
+pub struct TaskFailure {
+    pub name: &'static str,
+    pub error: anyhow::Error,
+}
+
+/// Running tasks of a group and the failures of the ones which already stopped.
+///
+/// Finished tasks are reaped whenever a new one is spawned, so groups with many short-lived tasks
+/// don't grow.
+#[derive(Debug, Default)]
+struct Tasks {
+    running: JoinSet<Result<()>>,
+    names: HashMap<task::Id, &'static str>,
+    failures: Vec<TaskFailure>,
+}
+
+impl Tasks {
+    fn reap(&mut self) {
+        while let Some(result) = self.running.try_join_next_with_id() {
+            self.finished(result);
+        }
+    }
+
+    /// Forgets the finished task and keeps its failure.
+    fn finished(&mut self, result: Result<(task::Id, Result<()>), JoinError>) {
+        let (id, error) = match result {
+            Ok((id, Ok(()))) => {
+                let name = self.names.remove(&id).unwrap_or_default();
+                debug!(task = name, "task stopped");
+                return;
+            }
+            Ok((id, Err(err))) => (id, err),
+            Err(err) => (err.id(), anyhow!(err)),
+        };
+
+        let name = self.names.remove(&id).unwrap_or_default();
+        self.failures.push(TaskFailure { name, error });
+    }
+}
+
+/// Background tasks which are stopped together when the node shuts down.
+///
+/// Tasks failing while the node is running are reported as [`Event::TaskFailed`].
+#[derive(Clone, Debug)]
+pub struct TaskGroup {
+    shutdown: CancellationToken,
+    tasks: Arc<Mutex<Tasks>>,
+    events_tx: broadcast::Sender<Event>,
+}
+
+impl TaskGroup {
+    pub fn new(events_tx: broadcast::Sender<Event>) -> Self {
+        Self {
+            shutdown: CancellationToken::new(),
+            tasks: Arc::default(),
+            events_tx,
+        }
+    }
+
+    /// Spawns a task which is dropped as soon as the group shuts down.
+    pub fn spawn<F>(&self, name: &'static str, task: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.spawn_graceful(name, |shutdown| async move {
+            tokio::select! {
+                result = task => result,
+                _ = shutdown.cancelled() => Ok(()),
+            }
+        });
+    }
+
+    /// Spawns a task which stops by itself when the given token is cancelled, for example after
+    /// flushing pending data.
+    pub fn spawn_graceful<F, T>(&self, name: &'static str, task: F)
+    where
+        F: FnOnce(CancellationToken) -> T,
+        T: Future<Output = Result<()>> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let events_tx = self.events_tx.clone();
+        let task = task(self.shutdown.clone());
+        let task = async move {
+            let result = task.await;
+
+            // Failures during shutdown are returned from there.
+            if let Err(err) = &result {
+                if !shutdown.is_cancelled() {
+                    error!(task = name, "task failed: {err:#}");
+                    let _ = events_tx.send(Event::TaskFailed {
+                        task: name,
+                        error: format!("{err:#}"),
+                    });
+                }
+            }
+
+            result
+        };
+
+        let mut tasks = self.tasks.lock().expect("tasks lock");
+        tasks.reap();
+        let id = tasks.running.spawn(task).id();
+        tasks.names.insert(id, name);
+    }
+
+    /// Token which is cancelled when the group shuts down.
+    pub fn token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Asks all tasks to stop, waits for them and returns the ones which failed.
+    ///
+    /// Tasks which don't stop in time are aborted.
+    pub async fn shutdown(&self) -> Vec<TaskFailure> {
+        self.shutdown.cancel();
+
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().expect("tasks lock"));
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+
+        loop {
+            match time::timeout_at(deadline, tasks.running.join_next_with_id()).await {
+                Ok(Some(result)) => tasks.finished(result),
+                Ok(None) => break,
+                Err(_) => {
+                    tasks.running.abort_all();
+                    for name in tasks.names.drain().map(|(_, name)| name) {
+                        tasks.failures.push(TaskFailure {
+                            name,
+                            error: anyhow!("task did not stop in time"),
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+
+        for failure in &tasks.failures {
+            error!(task = failure.name, "task failed: {:#}", failure.error);
+        }
+
+        tasks.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use tokio::sync::broadcast;
+
+    use crate::events::Event;
+
+    use super::TaskGroup;
+
+    #[tokio::test]
+    async fn report_failed_tasks() {
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        let tasks = TaskGroup::new(events_tx);
+
+        tasks.spawn("failing", async { bail!("broken") });
+
+        let event = events_rx.recv().await.unwrap();
+        assert!(matches!(
+            event,
+            Event::TaskFailed { task: "failing", error } if error == "broken"
+        ));
+
+        let failures = tasks.shutdown().await;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "failing");
+    }
+
+    #[tokio::test]
+    async fn return_failures_during_shutdown() {
+        let (events_tx, mut events_rx) = broadcast::channel(16);
+        let tasks = TaskGroup::new(events_tx);
+
+        tasks.spawn_graceful("graceful", |shutdown| async move {
+            shutdown.cancelled().await;
+            bail!("could not flush")
+        });
+        tasks.spawn("stopped", std::future::pending());
+
+        let failures = tasks.shutdown().await;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "graceful");
+
+        // Failures during shutdown are not reported as events.
+        assert!(events_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reap_finished_tasks() {
+        let (events_tx, _) = broadcast::channel(16);
+        let tasks = TaskGroup::new(events_tx);
+
+        for _ in 0..100 {
+            tasks.spawn("short-lived", async { Ok(()) });
+            tokio::task::yield_now().await;
+        }
+        tasks.spawn("failing", async { bail!("broken") });
+        tokio::task::yield_now().await;
+        tasks.spawn("running", std::future::pending());
+
+        {
+            let tasks = tasks.tasks.lock().unwrap();
+            assert_eq!(tasks.running.len(), 1);
+            assert_eq!(tasks.failures.len(), 1);
+        }
+
+        let failures = tasks.shutdown().await;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "failing");
+    }
+}
@@ -0,0 +1,316 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error};
+
+use crate::bridge::{run_bridge, ConnectionBridge, ConnectionInput, Frame};
+use crate::events::{report_bridge_error, Event};
+use crate::message::{EnvelopeFormat, Message};
+use crate::node::{PublishRequest, MAX_PUBLISH_SIZE};
+use crate::tasks::TaskGroup;
+
+/// Maximum size of a frame we accept from local applications.
+///
+/// Leaves room for the envelope fields around the largest payload which can be published. Payloads
+/// are hex-encoded in JSON envelopes, so only half as large payloads fit into them.
+pub const MAX_FRAME_SIZE: usize = MAX_PUBLISH_SIZE + MAX_ENVELOPE_OVERHEAD;
+
+/// Space for the envelope fields around a payload.
+const MAX_ENVELOPE_OVERHEAD: usize = 1024 * 4;
+
+/// TCP server for local applications which sends and receives length-delimited frames.
+///
+/// Every frame is prefixed with its length as an unsigned 32-bit big-endian integer. Frames sent
+/// to the server are published, every message received from the network is sent to all connected
+/// clients. Every connection is its own bridge.
+#[derive(Clone, Debug)]
+pub struct TcpServer {
+    pub publish_tx: mpsc::Sender<PublishRequest>,
+    pub messages_tx: broadcast::Sender<Message>,
+    pub events_tx: broadcast::Sender<Event>,
+    pub input: Option<EnvelopeFormat>,
+    pub envelope: Option<EnvelopeFormat>,
+}
+
+impl TcpServer {
+    pub fn spawn(self, listener: TcpListener, tasks: &TaskGroup) {
+        let connection_tasks = tasks.clone();
+        tasks.spawn_graceful("tcp", |shutdown| async move {
+            // Wait for all connections to deliver their pending messages on shutdown.
+            let connections = TaskTracker::new();
+
+            loop {
+                let result = tokio::select! {
+                    result = listener.accept() => result,
+                    _ = shutdown.cancelled() => break,
+                };
+
+                let (stream, addr) = match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        error!("tcp server error on accept: {err}");
+                        report_bridge_error(&self.events_tx, "tcp", &err);
+                        continue;
+                    }
+                };
+
+                debug!(%addr, "new tcp connection");
+                let connection = spawn_connection(
+                    stream,
+                    addr,
+                    self.input,
+                    self.envelope,
+                    self.events_tx.clone(),
+                    &connection_tasks,
+                );
+                connections.spawn(run_bridge(
+                    connection,
+                    self.publish_tx.clone(),
+                    self.messages_tx.subscribe(),
+                    self.events_tx.clone(),
+                    shutdown.clone(),
+                ));
+            }
+
+            connections.close();
+            connections.wait().await;
+
+            Ok(())
+        });
+    }
+}
+
+/// Connection of a local application to the TCP server.
+pub type TcpBridge = ConnectionBridge<Vec<u8>>;
+
+impl Frame for Vec<u8> {
+    fn encode(message: &Message, envelope: Option<EnvelopeFormat>) -> Result<Self> {
+        message.to_bytes(envelope)
+    }
+
+    fn error(bytes: Vec<u8>, _format: EnvelopeFormat) -> Result<Self> {
+        Ok(bytes)
+    }
+}
+
+/// Reads and writes frames of the connection in tasks of the given group.
+fn spawn_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    input: Option<EnvelopeFormat>,
+    envelope: Option<EnvelopeFormat>,
+    events_tx: broadcast::Sender<Event>,
+    tasks: &TaskGroup,
+) -> TcpBridge {
+    let (reader, writer) = stream.into_split();
+    ConnectionBridge::spawn(
+        "tcp",
+        tasks,
+        input,
+        envelope,
+        events_tx,
+        move |connection| async move {
+            read_frames(reader, connection)
+                .await
+                .with_context(|| format!("tcp connection {addr}"))
+        },
+        |writer_rx| write_frames(writer, writer_rx),
+    )
+}
+
+/// Reads frames from the local application until the connection is closed.
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    connection: ConnectionInput<Vec<u8>>,
+) -> Result<()> {
+    while let Some(buf) = read_frame(&mut reader, MAX_FRAME_SIZE).await? {
+        connection.push(&buf, connection.input()).await?;
+    }
+    Ok(())
+}
+
+/// Reads the next length-prefixed frame, returns nothing when the reader was closed.
+///
+/// The buffer grows while the frame arrives, so announcing a large frame without sending it
+/// doesn't make us allocate memory for it.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    if len > max_len {
+        bail!("frame of {len} bytes exceeds max. frame size of {max_len} bytes");
+    }
+
+    let mut buf = Vec::new();
+    reader
+        .take(len as u64)
+        .read_to_end(&mut buf)
+        .await
+        .context("could not read frame")?;
+    if buf.len() < len {
+        bail!(
+            "connection closed after {} of {len} bytes of the frame",
+            buf.len()
+        );
+    }
+
+    Ok(Some(buf))
+}
+
+/// Writes the bytes as a length-prefixed frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_u32(bytes.len() as u32).await?;
+    writer.write_all(bytes).await?;
+    Ok(())
+}
+
+async fn write_frames(
+    mut writer: OwnedWriteHalf,
+    mut writer_rx: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    while let Some(bytes) = writer_rx.recv().await {
+        write_frame(&mut writer, &bytes).await?;
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use p2panda_core::{Hash, PrivateKey};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{broadcast, mpsc};
+    use tokio::time::timeout;
+
+    use crate::message::Message;
+    use crate::node::PublishRequest;
+    use crate::tasks::TaskGroup;
+    use crate::topic::Topic;
+
+    use super::{read_frame, write_frame, TcpServer, MAX_FRAME_SIZE};
+
+    fn message(payload: &[u8]) -> Message {
+        Message {
+            public_key: PrivateKey::new().public_key(),
+            seq_num: 0,
+            timestamp: 1737801000,
+            hash: Hash::new(payload),
+            topic: Topic::from_str("chat").unwrap(),
+            content_type: None,
+            subject: None,
+            payload: payload.to_vec(),
+        }
+    }
+
+    /// Sends a frame from the client and waits for the server to publish it.
+    async fn publish(
+        client: &mut TcpStream,
+        publish_rx: &mut mpsc::Receiver<PublishRequest>,
+        payload: &[u8],
+    ) {
+        write_frame(client, payload).await.unwrap();
+        let request = timeout(Duration::from_secs(5), publish_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.message.payload, payload);
+    }
+
+    async fn receive(client: &mut TcpStream) -> Option<Vec<u8>> {
+        timeout(Duration::from_secs(5), read_frame(client, MAX_FRAME_SIZE))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn read_written_frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").await.unwrap();
+        write_frame(&mut buf, b"").await.unwrap();
+        assert_eq!(buf[..4], 5u32.to_be_bytes());
+
+        let mut reader = buf.as_slice();
+        assert_eq!(
+            read_frame(&mut reader, 5).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_frame(&mut reader, 5).await.unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut reader, 5).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reject_truncated_frame() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").await.unwrap();
+        buf.truncate(buf.len() - 2);
+
+        assert!(read_frame(&mut buf.as_slice(), 5).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_frame() {
+        // Only the length is sent, the frame is rejected before reading any of it.
+        let buf = u32::MAX.to_be_bytes();
+        assert!(read_frame(&mut buf.as_slice(), 5).await.is_err());
+
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello!").await.unwrap();
+        assert!(read_frame(&mut buf.as_slice(), 5).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn publish_and_deliver_frames() {
+        let (publish_tx, mut publish_rx) = mpsc::channel(16);
+        let (messages_tx, _) = broadcast::channel(16);
+        let (events_tx, _) = broadcast::channel(16);
+        let tasks = TaskGroup::new(events_tx.clone());
+        let server = TcpServer {
+            publish_tx,
+            messages_tx: messages_tx.clone(),
+            events_tx,
+            input: None,
+            envelope: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.spawn(listener, &tasks);
+
+        // Both connections are running once they published something.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        publish(&mut client, &mut publish_rx, b"hello").await;
+        publish(&mut other, &mut publish_rx, b"hi").await;
+
+        messages_tx.send(message(b"world")).unwrap();
+        assert_eq!(receive(&mut client).await, Some(b"world".to_vec()));
+        assert_eq!(receive(&mut other).await, Some(b"world".to_vec()));
+
+        // Oversized frames close the connection before they are read.
+        let len = u32::try_from(MAX_FRAME_SIZE + 1).unwrap();
+        other.write_all(&len.to_be_bytes()).await.unwrap();
+        assert_eq!(receive(&mut other).await, None);
+
+        // Other clients are not affected.
+        publish(&mut client, &mut publish_rx, b"still there").await;
+        messages_tx.send(message(b"again")).unwrap();
+        assert_eq!(receive(&mut client).await, Some(b"again".to_vec()));
+
+        assert!(tasks.shutdown().await.is_empty());
+    }
+}
@@ -14,8 +14,13 @@ use tokio::sync::RwLock;
 
 /// Every meshpit peer writes to one single log per topic which is identified by the node's public
 /// key and the topic id.
+///
+/// Ephemeral messages are written to a second log per topic which is always pruned and never
+/// synced.
 pub type LogId = [u8; 32];
 
+const EPHEMERAL_LOG_SUFFIX: &str = "ephemeral";
+
 /// Nodes converge around topics they are interested in to exchange data.
 ///
 /// In meshpit a topic is a simple string we convert to a BLAKE3 hash. If two peers are interested
@@ -27,6 +32,32 @@ impl Topic {
     pub fn new(topic_id: [u8; 32]) -> Self {
         Self(topic_id)
     }
+
+    /// Returns the topic of the given log.
+    pub fn from_log_id(log_id: LogId, ephemeral: bool) -> Self {
+        if ephemeral {
+            Self(xor(log_id, ephemeral_mask()))
+        } else {
+            Self(log_id)
+        }
+    }
+
+    /// Id of the log we write ephemeral messages of this topic to.
+    pub fn ephemeral_log_id(&self) -> LogId {
+        xor(self.0, ephemeral_mask())
+    }
+}
+
+fn ephemeral_mask() -> [u8; 32] {
+    Hash::new(EPHEMERAL_LOG_SUFFIX.as_bytes()).into()
+}
+
+fn xor(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
+    let mut result = [0; 32];
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    result
 }
 
 impl fmt::Display for Topic {
@@ -8,6 +8,9 @@ use tracing_subscriber::EnvFilter;
 
 /// Setup logging with the help of the `tracing` crate.
 ///
+/// Logs are written to stderr, so they don't get mixed up with data written to stdout in pipe
+/// mode.
+///
 /// The verbosity and targets can be configured with a filter string:
 ///
 /// 1. When no filter is set the default "meshpit=INFO" filter will be applied
@@ -35,7 +38,7 @@ pub fn setup_tracing(filter: String) {
     let filter = builder.parse_lossy(filter);
 
     tracing_subscriber::registry()
-        .with(Layer::default())
+        .with(Layer::default().with_writer(std::io::stderr))
         .with(filter)
         .try_init()
         .ok();
@@ -0,0 +1,668 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, error, warn};
+
+use crate::bridge::{check_datagram_size, decode_input, error_reply, Bridge};
+use crate::message::{EnvelopeFormat, Message, OutgoingMessage};
+use crate::node::{Config, JoinRequest};
+use crate::osc::OscConfig;
+use crate::topic::Topic;
+
+const SUBSCRIBE_COMMAND: &str = "meshpit:subscribe";
+
+const UNSUBSCRIBE_COMMAND: &str = "meshpit:unsubscribe";
+
+/// Largest payload which fits into a single UDP datagram over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Keeps track of the addresses which recently sent datagrams to the UDP server.
+///
+/// Senders which have been quiet for longer than the expiry duration are forgotten.
+#[derive(Debug)]
+pub struct Senders {
+    expiry: Duration,
+    last_seen: HashMap<SocketAddr, Instant>,
+}
+
+impl Senders {
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            expiry,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn seen(&mut self, addr: SocketAddr) {
+        if self.last_seen.insert(addr, Instant::now()).is_none() {
+            debug!(%addr, "new udp sender");
+        }
+    }
+
+    /// Refreshes the sender if we know it already.
+    pub fn refresh(&mut self, addr: SocketAddr) {
+        if let Some(last_seen) = self.last_seen.get_mut(&addr) {
+            *last_seen = Instant::now();
+        }
+    }
+
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.last_seen.remove(&addr);
+    }
+
+    /// Returns all senders which are not expired yet.
+    pub fn active(&mut self) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        self.last_seen.retain(|addr, last_seen| {
+            let active = now.duration_since(*last_seen) < self.expiry;
+            if !active {
+                debug!(%addr, "udp sender expired");
+            }
+            active
+        });
+        self.last_seen.keys().copied().collect()
+    }
+}
+
+/// Control datagrams local programs can send to the UDP server to register themselves.
+///
+/// Control datagrams are text messages in the form of "meshpit:subscribe [topic]" or
+/// "meshpit:unsubscribe [topic]". When no topic is given the address subscribes to all topics or
+/// unsubscribes from everything. Subscribing to a topic joins it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Control {
+    Subscribe(Option<Topic>),
+    Unsubscribe(Option<Topic>),
+}
+
+impl Control {
+    /// Returns the control command if the datagram contains one.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?.trim();
+        let (command, topic) = match text.split_once(char::is_whitespace) {
+            Some((command, topic)) => (command, Some(topic.trim())),
+            None => (text, None),
+        };
+        let topic = topic.map(|topic| Topic::from_str(topic).expect("topic from any string"));
+
+        match command {
+            SUBSCRIBE_COMMAND => Some(Self::Subscribe(topic)),
+            UNSUBSCRIBE_COMMAND => Some(Self::Unsubscribe(topic)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Filter {
+    All,
+    Topics(HashSet<Topic>),
+}
+
+/// Addresses which subscribed themselves to incoming data with control datagrams.
+///
+/// Subscriptions expire just like senders, unless the subscribed address keeps sending datagrams
+/// to the UDP server, for example the subscribe command again.
+#[derive(Debug)]
+pub struct Subscribers {
+    filters: HashMap<SocketAddr, Filter>,
+    senders: Senders,
+}
+
+impl Subscribers {
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            filters: HashMap::new(),
+            senders: Senders::new(expiry),
+        }
+    }
+
+    /// Keeps the subscription of the address alive.
+    pub fn refresh(&mut self, addr: SocketAddr) {
+        self.senders.refresh(addr);
+    }
+
+    pub fn handle(&mut self, addr: SocketAddr, control: Control) {
+        self.senders.seen(addr);
+
+        match control {
+            Control::Subscribe(None) => {
+                debug!(%addr, "subscribe to all topics");
+                self.filters.insert(addr, Filter::All);
+            }
+            Control::Subscribe(Some(topic)) => {
+                debug!(%addr, %topic, "subscribe to topic");
+                let filter = self
+                    .filters
+                    .entry(addr)
+                    .or_insert_with(|| Filter::Topics(HashSet::new()));
+                if let Filter::Topics(topics) = filter {
+                    topics.insert(topic);
+                }
+            }
+            Control::Unsubscribe(None) => {
+                debug!(%addr, "unsubscribe");
+                self.filters.remove(&addr);
+                self.senders.forget(addr);
+            }
+            Control::Unsubscribe(Some(topic)) => match self.filters.get_mut(&addr) {
+                Some(Filter::Topics(topics)) => {
+                    debug!(%addr, %topic, "unsubscribe from topic");
+                    topics.remove(&topic);
+                    if topics.is_empty() {
+                        self.filters.remove(&addr);
+                        self.senders.forget(addr);
+                    }
+                }
+                Some(Filter::All) => {
+                    warn!(%addr, %topic, "can't unsubscribe from single topic when subscribed to all");
+                }
+                None => (),
+            },
+        }
+    }
+
+    /// Returns all addresses interested in data from the given topic, expired subscriptions are
+    /// removed.
+    pub fn matching(&mut self, topic: &Topic) -> Vec<SocketAddr> {
+        let active = self.senders.active();
+        self.filters.retain(|addr, _| active.contains(addr));
+
+        self.filters
+            .iter()
+            .filter(|(_, filter)| match filter {
+                Filter::All => true,
+                Filter::Topics(topics) => topics.contains(topic),
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// Binds an UDP socket for sending data to a multicast group or a broadcast address.
+///
+/// The TTL limits how many hops multicast datagrams travel, the interface selects which network
+/// interface is used for sending them (only supported for IPv4). Other unicast addresses are
+/// rejected, they are most likely a mistake as the UDP clients can be used for them.
+pub fn bind_multicast(
+    addr: &SocketAddr,
+    ttl: u8,
+    interface: Option<Ipv4Addr>,
+) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    socket.bind(&bind_addr.into())?;
+
+    match addr {
+        SocketAddr::V4(addr) if addr.ip().is_multicast() => {
+            socket.set_multicast_ttl_v4(ttl.into())?;
+            if let Some(interface) = interface {
+                socket.set_multicast_if_v4(&interface)?;
+            }
+        }
+        SocketAddr::V4(addr) if is_broadcast(addr.ip()) => {
+            socket.set_broadcast(true)?;
+        }
+        SocketAddr::V4(_) => bail!("{addr} is not a multicast group or broadcast address"),
+        SocketAddr::V6(addr) if addr.ip().is_multicast() => {
+            if interface.is_some() {
+                bail!("multicast interface can only be set for IPv4 groups");
+            }
+            socket.set_multicast_hops_v6(ttl.into())?;
+        }
+        SocketAddr::V6(_) => bail!("{addr} is not a multicast group"),
+    }
+
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Returns true if the address is the limited broadcast address or the directed broadcast
+/// address of one of the local networks.
+fn is_broadcast(addr: &Ipv4Addr) -> bool {
+    addr.is_broadcast()
+        || netdev::get_interfaces()
+            .iter()
+            .flat_map(|interface| &interface.ipv4)
+            .any(|net| net.prefix_len < 31 && net.broadcast() == *addr)
+}
+
+/// UDP server receiving datagrams from local applications and UDP client sending received data to
+/// them.
+#[derive(Debug)]
+pub struct UdpBridge {
+    socket: UdpSocket,
+    multicast: Option<(UdpSocket, SocketAddr)>,
+    client_addrs: Vec<SocketAddr>,
+    reply_to_senders: bool,
+    senders: Senders,
+    subscriptions: bool,
+    subscribers: Subscribers,
+    input: Option<EnvelopeFormat>,
+    envelope: Option<EnvelopeFormat>,
+    osc: Option<OscConfig>,
+    max_message_size: usize,
+    buf: Vec<u8>,
+    join_tx: mpsc::Sender<JoinRequest>,
+    pending: Option<PendingSubscription>,
+}
+
+/// Subscription to a topic which waits until the node joined the topic.
+///
+/// It is kept in the bridge instead of being awaited while handling the datagram, receiving gets
+/// cancelled whenever a message is delivered.
+#[derive(Debug)]
+struct PendingSubscription {
+    addr: SocketAddr,
+    topic: Topic,
+    reply_rx: oneshot::Receiver<Result<()>>,
+}
+
+impl UdpBridge {
+    pub async fn bind(
+        addr: SocketAddr,
+        config: &Config,
+        join_tx: mpsc::Sender<JoinRequest>,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(addr).await.context("bind udp server")?;
+
+        // Optionally send received data to a multicast group or broadcast address instead.
+        let multicast = match config.udp_multicast_addr {
+            Some(addr) => Some((
+                bind_multicast(
+                    &addr,
+                    config.udp_multicast_ttl,
+                    config.udp_multicast_interface,
+                )
+                .context("bind udp multicast socket")?,
+                addr,
+            )),
+            None => None,
+        };
+
+        Ok(Self {
+            socket,
+            multicast,
+            client_addrs: config.udp_client_addrs.clone(),
+            reply_to_senders: config.udp_reply_to_senders,
+            senders: Senders::new(config.udp_sender_expiry),
+            subscriptions: config.udp_subscriptions,
+            subscribers: Subscribers::new(config.udp_subscription_expiry),
+            input: config.udp_input,
+            envelope: config.udp_envelope,
+            osc: config.osc.clone(),
+            max_message_size: config.max_message_size,
+            // Use a slightly larger buffer than needed to detect datagrams exceeding the limit,
+            // the operating system silently truncates them otherwise.
+            buf: vec![0; config.max_message_size + 1],
+            join_tx,
+            pending: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Handles a datagram and returns the message to publish, if there is any.
+    async fn handle_datagram(&mut self, len: usize, addr: SocketAddr) -> Option<OutgoingMessage> {
+        if let Err(err) = check_datagram_size(len, self.max_message_size) {
+            warn!(%addr, "drop datagram: {err}");
+            self.reply_error(addr, &err).await;
+            return None;
+        }
+
+        let bytes = &self.buf[..len];
+
+        if self.subscriptions {
+            if let Some(control) = Control::parse(bytes) {
+                self.handle_control(addr, control).await;
+                return None;
+            }
+            self.subscribers.refresh(addr);
+        }
+
+        if self.reply_to_senders {
+            self.senders.seen(addr);
+        }
+
+        // OSC can't be used together with input envelopes.
+        let result = match &self.osc {
+            Some(osc) => match osc.filter(bytes) {
+                Ok(Some(payload)) => decode_input(None, &payload),
+                Ok(None) => {
+                    debug!(%addr, "osc packet doesn't match any publish pattern");
+                    return None;
+                }
+                Err(err) => Err(err.context("invalid osc packet")),
+            },
+            None => decode_input(self.input, bytes),
+        };
+
+        match result {
+            Ok(message) => Some(message),
+            Err(err) => {
+                warn!(%addr, "drop datagram: {err:#}");
+                self.reply_error(addr, &format!("{err:#}")).await;
+                None
+            }
+        }
+    }
+
+    /// Subscriptions to a single topic only take effect after the node joined it, see
+    /// [`Self::finish_subscription`].
+    async fn handle_control(&mut self, addr: SocketAddr, control: Control) {
+        let topic = match control {
+            Control::Subscribe(Some(topic)) => topic,
+            control => {
+                self.subscribers.handle(addr, control);
+                return;
+            }
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let request = JoinRequest {
+            topic: topic.clone(),
+            reply_tx,
+        };
+        if self.join_tx.try_send(request).is_err() {
+            warn!(%addr, %topic, "reject subscription: node is busy joining other topics");
+            self.reply_error(addr, &format!("can not join topic {topic} right now"))
+                .await;
+            return;
+        }
+
+        self.pending = Some(PendingSubscription {
+            addr,
+            topic,
+            reply_rx,
+        });
+    }
+
+    /// Waits until the topic of a pending subscription was joined and subscribes the address to
+    /// it, the subscription is rejected when joining failed.
+    async fn finish_subscription(&mut self) {
+        let Some(pending) = &mut self.pending else {
+            return;
+        };
+        let result = (&mut pending.reply_rx)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("node is shutting down")));
+        let PendingSubscription { addr, topic, .. } =
+            self.pending.take().expect("pending subscription");
+
+        match result {
+            Ok(()) => self
+                .subscribers
+                .handle(addr, Control::Subscribe(Some(topic))),
+            Err(err) => {
+                warn!(%addr, %topic, "reject subscription: {err}");
+                self.reply_error(addr, &err).await;
+            }
+        }
+    }
+
+    /// Sends an error message back to the local application, if it is using envelopes.
+    async fn reply_error(&self, addr: SocketAddr, err: &impl fmt::Display) {
+        let Some(bytes) = error_reply(self.input, err) else {
+            return;
+        };
+
+        if let Err(err) = self.socket.send_to(&bytes, addr).await {
+            error!("udp error on send to {addr}: {err}");
+        }
+    }
+}
+
+#[async_trait]
+impl Bridge for UdpBridge {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>> {
+        loop {
+            self.finish_subscription().await;
+
+            let (len, addr) = match self.socket.recv_from(&mut self.buf).await {
+                Ok(result) => result,
+                Err(err) => {
+                    error!("udp server error on recv: {err}");
+                    continue;
+                }
+            };
+
+            if let Some(message) = self.handle_datagram(len, addr).await {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    async fn deliver(&mut self, message: &Message) -> Result<()> {
+        let bytes = message
+            .to_bytes(self.envelope)
+            .context("could not encode message envelope")?;
+
+        // Route OSC messages by their address, the remaining ones are sent to the UDP clients.
+        let bytes = match &self.osc {
+            Some(osc) => {
+                let routed = osc.route(&bytes).with_context(|| {
+                    format!("drop invalid osc packet from {}", message.public_key)
+                })?;
+
+                for (addr, bytes) in routed.routes {
+                    if let Err(err) = self.socket.send_to(&bytes, addr).await {
+                        error!("udp error on send to osc route {addr}: {err}");
+                    }
+                }
+
+                let Some(remaining) = routed.remaining else {
+                    return Ok(());
+                };
+                remaining
+            }
+            None => bytes,
+        };
+
+        if let Some((socket, addr)) = &self.multicast {
+            if let Err(err) = socket.send_to(&bytes, addr).await {
+                error!("udp error on send to multicast {addr}: {err}");
+            }
+        }
+
+        let mut addrs = if self.reply_to_senders {
+            self.senders.active()
+        } else if self.multicast.is_some() {
+            Vec::new()
+        } else {
+            self.client_addrs.clone()
+        };
+        for addr in self.subscribers.matching(&message.topic) {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        for addr in &addrs {
+            if let Err(err) = self.socket.send_to(&bytes, addr).await {
+                error!("udp error on send to client {addr}: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::sync::mpsc;
+    use tokio::time;
+
+    use crate::bridge::Bridge;
+    use crate::node::{Config, JoinRequest, TooManyTopics};
+    use crate::topic::Topic;
+
+    use super::{Control, Senders, Subscribers, UdpBridge};
+
+    const EXPIRY: Duration = Duration::from_secs(60);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn topic(name: &str) -> Topic {
+        Topic::from_str(name).unwrap()
+    }
+
+    #[test]
+    fn parse_control() {
+        assert_eq!(
+            Control::parse(b"meshpit:subscribe"),
+            Some(Control::Subscribe(None))
+        );
+        assert_eq!(
+            Control::parse(b"meshpit:subscribe chat\n"),
+            Some(Control::Subscribe(Some(topic("chat"))))
+        );
+        assert_eq!(
+            Control::parse(b" meshpit:unsubscribe  chat "),
+            Some(Control::Unsubscribe(Some(topic("chat"))))
+        );
+        assert_eq!(
+            Control::parse(b"meshpit:unsubscribe\n"),
+            Some(Control::Unsubscribe(None))
+        );
+
+        // Anything else is regular data.
+        assert_eq!(Control::parse(b"hello"), None);
+        assert_eq!(Control::parse(b"meshpit:subscribed"), None);
+        assert_eq!(Control::parse(b"MESHPIT:SUBSCRIBE"), None);
+        assert_eq!(Control::parse(&[0xff, 0xfe]), None);
+        assert_eq!(Control::parse(b""), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expire_senders() {
+        let mut senders = Senders::new(EXPIRY);
+        senders.seen(addr(1));
+        time::advance(EXPIRY / 2).await;
+        senders.seen(addr(2));
+        assert_eq!(senders.active().len(), 2);
+
+        time::advance(EXPIRY / 2).await;
+        assert_eq!(senders.active(), vec![addr(2)]);
+
+        // Refreshing keeps known senders alive, but doesn't add new ones.
+        senders.refresh(addr(1));
+        senders.refresh(addr(2));
+        time::advance(EXPIRY / 2).await;
+        assert_eq!(senders.active(), vec![addr(2)]);
+
+        senders.forget(addr(2));
+        assert!(senders.active().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn match_subscriptions() {
+        let mut subscribers = Subscribers::new(EXPIRY);
+        subscribers.handle(addr(1), Control::Subscribe(None));
+        subscribers.handle(addr(2), Control::Subscribe(Some(topic("chat"))));
+        subscribers.handle(addr(2), Control::Subscribe(Some(topic("news"))));
+
+        let mut matching = subscribers.matching(&topic("chat"));
+        matching.sort();
+        assert_eq!(matching, vec![addr(1), addr(2)]);
+        assert_eq!(subscribers.matching(&topic("other")), vec![addr(1)]);
+
+        // Unsubscribing from the last topic removes the subscription.
+        subscribers.handle(addr(2), Control::Unsubscribe(Some(topic("chat"))));
+        assert_eq!(subscribers.matching(&topic("chat")), vec![addr(1)]);
+        assert_eq!(subscribers.matching(&topic("news")).len(), 2);
+        subscribers.handle(addr(2), Control::Unsubscribe(Some(topic("news"))));
+        assert_eq!(subscribers.matching(&topic("news")), vec![addr(1)]);
+
+        // Subscriptions to all topics can only be removed as a whole.
+        subscribers.handle(addr(1), Control::Unsubscribe(Some(topic("chat"))));
+        assert_eq!(subscribers.matching(&topic("chat")), vec![addr(1)]);
+        subscribers.handle(addr(1), Control::Unsubscribe(None));
+        assert!(subscribers.matching(&topic("chat")).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expire_subscriptions() {
+        let mut subscribers = Subscribers::new(EXPIRY);
+        subscribers.handle(addr(1), Control::Subscribe(None));
+        subscribers.handle(addr(2), Control::Subscribe(None));
+
+        time::advance(EXPIRY / 2).await;
+        subscribers.refresh(addr(1));
+        time::advance(EXPIRY / 2).await;
+        assert_eq!(subscribers.matching(&topic("chat")), vec![addr(1)]);
+
+        // Expired subscriptions are gone for good.
+        subscribers.refresh(addr(2));
+        time::advance(EXPIRY).await;
+        assert!(subscribers.matching(&topic("chat")).is_empty());
+    }
+
+    /// Binds a bridge with subscriptions whose join requests are answered by `join`.
+    async fn bridge(join: fn(&Topic) -> anyhow::Result<()>) -> (UdpBridge, UdpSocket) {
+        let (join_tx, mut join_rx) = mpsc::channel::<JoinRequest>(16);
+        tokio::spawn(async move {
+            while let Some(request) = join_rx.recv().await {
+                let _ = request.reply_tx.send(join(&request.topic));
+            }
+        });
+
+        let config = Config {
+            udp_subscriptions: true,
+            ..Config::default()
+        };
+        let bridge = UdpBridge::bind(addr(0), &config, join_tx).await.unwrap();
+        let client = UdpSocket::bind(addr(0)).await.unwrap();
+        client.connect(bridge.local_addr().unwrap()).await.unwrap();
+        (bridge, client)
+    }
+
+    #[tokio::test]
+    async fn join_topics_of_subscriptions() {
+        let (mut bridge, client) = bridge(|_| Ok(())).await;
+        client.send(b"meshpit:subscribe chat").await.unwrap();
+        client.send(b"hello").await.unwrap();
+
+        let message = bridge.recv().await.unwrap().unwrap();
+        assert_eq!(message.payload, b"hello");
+        assert_eq!(
+            bridge.subscribers.matching(&topic("chat")),
+            vec![client.local_addr().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_subscriptions_to_unjoined_topics() {
+        let (mut bridge, client) = bridge(|topic| Err(TooManyTopics(topic.clone()).into())).await;
+        client.send(b"meshpit:subscribe chat").await.unwrap();
+        client.send(b"hello").await.unwrap();
+
+        bridge.recv().await.unwrap().unwrap();
+        assert!(bridge.subscribers.matching(&topic("chat")).is_empty());
+    }
+}
@@ -0,0 +1,307 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::net::UnixDatagram;
+use tracing::{debug, error, warn};
+
+use crate::bridge::{check_datagram_size, decode_input, error_reply, Bridge};
+use crate::message::{EnvelopeFormat, Message, OutgoingMessage};
+use crate::node::Config;
+
+/// Unix datagram socket for local applications on the same host, with the same semantics as the
+/// UDP server and client.
+///
+/// Datagrams sent to the socket are published, every message received from the network is sent to
+/// the client sockets.
+#[derive(Debug)]
+pub struct UnixBridge {
+    socket: UnixDatagram,
+    client_paths: Vec<PathBuf>,
+    input: Option<EnvelopeFormat>,
+    envelope: Option<EnvelopeFormat>,
+    max_message_size: usize,
+    buf: Vec<u8>,
+}
+
+impl UnixBridge {
+    /// Binds an Unix datagram socket at the given path which can only be accessed by the current
+    /// user.
+    ///
+    /// Stale socket files from previous runs are removed, other files at the path are never
+    /// touched.
+    pub async fn bind(path: &Path, config: &Config) -> Result<Self> {
+        match fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                fs::remove_file(path)
+                    .await
+                    .context("remove stale unix socket")?;
+            }
+            Ok(_) => bail!("{} exists and is not a unix socket", path.display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        let socket = bind_private(path).await?;
+
+        Ok(Self {
+            socket,
+            client_paths: config.unix_client_paths.clone(),
+            input: config.udp_input,
+            envelope: config.udp_envelope,
+            max_message_size: config.max_message_size,
+            // Use a slightly larger buffer than needed to detect datagrams exceeding the limit.
+            buf: vec![0; config.max_message_size + 1],
+        })
+    }
+
+    /// Handles a datagram and returns the message to publish, if there is any.
+    ///
+    /// Only senders which are bound to a path can receive error messages.
+    async fn handle_datagram(
+        &self,
+        len: usize,
+        sender: Option<PathBuf>,
+    ) -> Option<OutgoingMessage> {
+        let result = check_datagram_size(len, self.max_message_size)
+            .and_then(|()| decode_input(self.input, &self.buf[..len]));
+
+        match result {
+            Ok(message) => Some(message),
+            Err(err) => {
+                warn!("drop datagram: {err}");
+                if let Some(sender) = &sender {
+                    self.reply_error(sender, &err).await;
+                }
+                None
+            }
+        }
+    }
+
+    /// Sends an error message back to the local application, if it is using envelopes.
+    async fn reply_error(&self, path: &Path, err: &impl fmt::Display) {
+        let Some(bytes) = error_reply(self.input, err) else {
+            return;
+        };
+
+        if let Err(err) = self.socket.send_to(&bytes, path).await {
+            error!("unix socket error on send to {}: {err}", path.display());
+        }
+    }
+}
+
+/// Binds the socket in a temporary directory only the current user can access and links it to the
+/// given path afterwards.
+///
+/// Setting the permissions right after binding at the final path would leave a short window in
+/// which other users could connect to the socket. Unlike renaming, linking fails when something
+/// was created at the path in the meantime instead of replacing it.
+async fn bind_private(path: &Path) -> Result<UnixDatagram> {
+    let file_name = path
+        .file_name()
+        .context("unix socket path has no file name")?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut dir_name = OsString::from(".");
+    dir_name.push(file_name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = parent.join(dir_name);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .await
+        .context("create temporary directory for unix socket")?;
+
+    let tmp_path = dir.join(file_name);
+    let result = async {
+        let socket = UnixDatagram::bind(&tmp_path)?;
+        fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o600)).await?;
+        fs::hard_link(&tmp_path, path)
+            .await
+            .with_context(|| format!("link unix socket to {}", path.display()))?;
+        Ok(socket)
+    }
+    .await;
+
+    let _ = fs::remove_file(&tmp_path).await;
+    let _ = fs::remove_dir(&dir).await;
+
+    result
+}
+
+#[async_trait]
+impl Bridge for UnixBridge {
+    fn name(&self) -> &'static str {
+        "unix"
+    }
+
+    async fn recv(&mut self) -> Result<Option<OutgoingMessage>> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut self.buf).await {
+                Ok(result) => result,
+                Err(err) => {
+                    error!("unix socket error on recv: {err}");
+                    continue;
+                }
+            };
+
+            let sender = addr.as_pathname().map(Path::to_path_buf);
+
+            if let Some(message) = self.handle_datagram(len, sender).await {
+                return Ok(Some(message));
+            }
+        }
+    }
+
+    async fn deliver(&mut self, message: &Message) -> Result<()> {
+        let bytes = message
+            .to_bytes(self.envelope)
+            .context("could not encode message envelope")?;
+
+        for path in &self.client_paths {
+            // Fails when the client is not listening (yet), don't flood the logs.
+            if let Err(err) = self.socket.send_to(&bytes, path).await {
+                debug!(
+                    "unix socket error on send to client {}: {err}",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use p2panda_core::{Hash, PrivateKey};
+    use tokio::fs;
+    use tokio::net::UnixDatagram;
+
+    use crate::bridge::Bridge;
+    use crate::message::Message;
+    use crate::node::Config;
+    use crate::topic::Topic;
+
+    use super::{bind_private, UnixBridge};
+
+    /// Creates an empty directory for the sockets of a test.
+    async fn socket_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("meshpit-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir).await;
+        fs::create_dir(&dir).await.unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn replace_stale_socket() {
+        let dir = socket_dir("stale").await;
+        let path = dir.join("meshpit.sock");
+        drop(StdUnixDatagram::bind(&path).unwrap());
+
+        let bridge = UnixBridge::bind(&path, &Config::default()).await.unwrap();
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"hello", &path).await.unwrap();
+        drop(bridge);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuse_other_files() {
+        let dir = socket_dir("file").await;
+        let path = dir.join("meshpit.sock");
+        fs::write(&path, b"keep me").await.unwrap();
+
+        assert!(UnixBridge::bind(&path, &Config::default()).await.is_err());
+        assert_eq!(fs::read(&path).await.unwrap(), b"keep me");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_replace_files_created_while_binding() {
+        let dir = socket_dir("race").await;
+        let path = dir.join("meshpit.sock");
+
+        // Created after the path was checked.
+        fs::write(&path, b"keep me").await.unwrap();
+        assert!(bind_private(&path).await.is_err());
+        assert_eq!(fs::read(&path).await.unwrap(), b"keep me");
+
+        // Nothing is left behind.
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        entries.next_entry().await.unwrap().unwrap();
+        assert!(entries.next_entry().await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_owner_can_access_socket() {
+        let dir = socket_dir("mode").await;
+        let path = dir.join("meshpit.sock");
+        let _bridge = UnixBridge::bind(&path, &Config::default()).await.unwrap();
+
+        let metadata = fs::symlink_metadata(&path).await.unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        // The temporary directory is gone.
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        assert_eq!(
+            entries.next_entry().await.unwrap().unwrap().file_name(),
+            "meshpit.sock"
+        );
+        assert!(entries.next_entry().await.unwrap().is_none());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_and_deliver_datagrams() {
+        let dir = socket_dir("round-trip").await;
+        let path = dir.join("meshpit.sock");
+        let client_path = dir.join("client.sock");
+        let config = Config {
+            unix_client_paths: vec![client_path.clone()],
+            ..Config::default()
+        };
+        let mut bridge = UnixBridge::bind(&path, &config).await.unwrap();
+        let client = UnixDatagram::bind(&client_path).unwrap();
+
+        client.send_to(b"hello", &path).await.unwrap();
+        let message = bridge.recv().await.unwrap().unwrap();
+        assert_eq!(message.payload, b"hello");
+
+        let message = Message {
+            public_key: PrivateKey::new().public_key(),
+            seq_num: 0,
+            timestamp: 1737801000,
+            hash: Hash::new(b"operation"),
+            topic: Topic::from_str("chat").unwrap(),
+            content_type: None,
+            subject: None,
+            payload: b"world".to_vec(),
+        };
+        bridge.deliver(&message).await.unwrap();
+        let mut buf = [0; 16];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"world");
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Extension, Router};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, warn};
+
+use crate::bridge::{run_bridge, ConnectionBridge, ConnectionInput, Frame};
+use crate::events::{report_bridge_error, Event};
+use crate::message::{EnvelopeFormat, Message};
+use crate::node::PublishRequest;
+use crate::tasks::TaskGroup;
+use crate::tcp::MAX_FRAME_SIZE;
+
+/// WebSocket server for browser-based applications.
+///
+/// Binary frames sent to the server are handled like datagrams sent to the UDP server, text frames
+/// are always expected to be JSON envelopes. Every message received from the network is sent to
+/// all connected clients, as a text frame when JSON envelopes are used and as a binary frame
+/// otherwise. Every connection is its own bridge.
+#[derive(Clone, Debug)]
+pub struct WebSocketServer {
+    pub publish_tx: mpsc::Sender<PublishRequest>,
+    pub messages_tx: broadcast::Sender<Message>,
+    pub events_tx: broadcast::Sender<Event>,
+    pub input: Option<EnvelopeFormat>,
+    pub envelope: Option<EnvelopeFormat>,
+    pub allowed_origins: Vec<String>,
+}
+
+impl WebSocketServer {
+    pub fn spawn(self, listener: TcpListener, tasks: &TaskGroup) {
+        let events_tx = self.events_tx.clone();
+        // Upgraded connections are not part of the graceful shutdown of the server, we wait for
+        // them to deliver their pending messages ourselves.
+        let connections = TaskTracker::new();
+        let router = Router::new()
+            .route("/", get(upgrade))
+            .layer(Extension(tasks.clone()))
+            .layer(Extension(connections.clone()))
+            .with_state(self);
+
+        tasks.spawn_graceful("websocket", |shutdown| async move {
+            let result = axum::serve(listener, router)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await;
+            if let Err(err) = &result {
+                error!("websocket server error: {err}");
+                report_bridge_error(&events_tx, "websocket", err);
+            }
+
+            connections.close();
+            connections.wait().await;
+
+            Ok(result?)
+        });
+    }
+}
+
+/// WebSocket connection of a browser-based application.
+pub type WebSocketBridge = ConnectionBridge<WsMessage>;
+
+/// JSON envelopes are sent as text frames, everything else as binary frames.
+impl Frame for WsMessage {
+    fn encode(message: &Message, envelope: Option<EnvelopeFormat>) -> Result<Self> {
+        let bytes = message.to_bytes(envelope)?;
+        match envelope {
+            Some(format) => Self::error(bytes, format),
+            None => Ok(WsMessage::Binary(bytes.into())),
+        }
+    }
+
+    fn error(bytes: Vec<u8>, format: EnvelopeFormat) -> Result<Self> {
+        match format {
+            EnvelopeFormat::Json => Ok(WsMessage::Text(String::from_utf8(bytes)?.into())),
+            EnvelopeFormat::Cbor => Ok(WsMessage::Binary(bytes.into())),
+        }
+    }
+}
+
+/// Reads frames from the browser until the connection is closed.
+async fn read_frames(
+    mut stream: SplitStream<WebSocket>,
+    connection: ConnectionInput<WsMessage>,
+) -> Result<()> {
+    while let Some(frame) = stream.next().await {
+        match frame {
+            Ok(WsMessage::Binary(bytes)) => connection.push(&bytes, connection.input()).await?,
+            Ok(WsMessage::Text(text)) => {
+                connection
+                    .push(text.as_bytes(), Some(EnvelopeFormat::Json))
+                    .await?
+            }
+            // Ping and pong frames are handled by the WebSocket implementation.
+            Ok(_) => continue,
+            Err(err) => {
+                debug!("websocket connection error: {err}");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn write_frames(
+    mut sink: SplitSink<WebSocket, WsMessage>,
+    mut writer_rx: mpsc::Receiver<WsMessage>,
+) -> Result<()> {
+    while let Some(frame) = writer_rx.recv().await {
+        sink.send(frame).await?;
+    }
+    sink.send(WsMessage::Close(None)).await?;
+    Ok(())
+}
+
+async fn upgrade(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    State(server): State<WebSocketServer>,
+    Extension(tasks): Extension<TaskGroup>,
+    Extension(connections): Extension<TaskTracker>,
+) -> Response {
+    if !is_allowed_origin(&headers, &server.allowed_origins) {
+        warn!(origin = ?headers.get(header::ORIGIN), "reject websocket connection from origin");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    debug!("new websocket connection");
+    ws.max_message_size(MAX_FRAME_SIZE)
+        .on_upgrade(move |socket| {
+            let (sink, stream) = socket.split();
+            let bridge: WebSocketBridge = ConnectionBridge::spawn(
+                "websocket",
+                &tasks,
+                server.input,
+                server.envelope,
+                server.events_tx.clone(),
+                |connection| read_frames(stream, connection),
+                |writer_rx| write_frames(sink, writer_rx),
+            );
+            let connection = run_bridge(
+                bridge,
+                server.publish_tx,
+                server.messages_tx.subscribe(),
+                server.events_tx,
+                tasks.token(),
+            );
+            async move {
+                let _ = connections.track_future(connection).await;
+            }
+        })
+}
+
+/// Returns true if browsers may send requests to us from the origin of the request.
+///
+/// Requests without an origin don't come from browsers and are allowed. Without an allow-list only
+/// pages served from the same host (on any port) are allowed, "*" allows all origins.
+///
+/// Any website can make its name resolve to our address (DNS rebinding), its pages then look like
+/// they were served from the same host. Without an allow-list we only accept requests addressed to
+/// "localhost" or an IP address, these can't be rebound.
+pub fn is_allowed_origin(headers: &HeaderMap, allowed_origins: &[String]) -> bool {
+    let origin = match headers.get(header::ORIGIN).map(|origin| origin.to_str()) {
+        Some(Ok(origin)) => Some(origin),
+        Some(Err(_)) => return false,
+        None => None,
+    };
+
+    if !allowed_origins.is_empty() {
+        return origin.is_none_or(|origin| {
+            allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        });
+    }
+
+    let Some(host) = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(hostname)
+    else {
+        return origin.is_none();
+    };
+    if !host.eq_ignore_ascii_case("localhost") && host.parse::<IpAddr>().is_err() {
+        return false;
+    }
+
+    let Some(origin) = origin else {
+        return true;
+    };
+
+    // Sandboxed pages and local files send "null" as their origin, they are never the same host.
+    origin
+        .split_once("://")
+        .is_some_and(|(_, origin_host)| hostname(origin_host).eq_ignore_ascii_case(host))
+}
+
+/// Removes the port from a "host:port" string.
+fn hostname(authority: &str) -> &str {
+    match authority.strip_prefix('[') {
+        // IPv6 addresses are enclosed in brackets.
+        Some(rest) => rest.split_once(']').map_or(authority, |(host, _)| host),
+        None => authority
+            .split_once(':')
+            .map_or(authority, |(host, _)| host),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use super::is_allowed_origin;
+
+    fn headers(host: &'static str, origin: Option<&'static str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static(host));
+        if let Some(origin) = origin {
+            headers.insert(header::ORIGIN, HeaderValue::from_static(origin));
+        }
+        headers
+    }
+
+    #[test]
+    fn allow_same_host() {
+        let allowed = [
+            headers("localhost:41416", None),
+            headers("localhost:41416", Some("http://localhost:8080")),
+            headers("LOCALHOST:41416", Some("https://localhost")),
+            headers("[::1]:41416", Some("http://[::1]:8080")),
+        ];
+        for headers in allowed {
+            assert!(is_allowed_origin(&headers, &[]), "{headers:?}");
+        }
+
+        let rejected = [
+            headers("localhost:41416", Some("http://example.org")),
+            headers("127.0.0.1:41416", Some("http://localhost:8080")),
+            headers("localhost:41416", Some("null")),
+        ];
+        for headers in rejected {
+            assert!(!is_allowed_origin(&headers, &[]), "{headers:?}");
+        }
+    }
+
+    #[test]
+    fn reject_rebound_hosts() {
+        // A page of evil.example whose name was rebound to our address.
+        let rejected = [
+            headers("evil.example:41416", Some("http://evil.example:41416")),
+            headers("evil.example:41416", None),
+        ];
+        for headers in rejected {
+            assert!(!is_allowed_origin(&headers, &[]), "{headers:?}");
+        }
+
+        // Listed origins are trusted no matter which host they address.
+        let allowed_origins = ["http://evil.example:41416".to_string()];
+        let headers = headers("evil.example:41416", Some("http://evil.example:41416"));
+        assert!(is_allowed_origin(&headers, &allowed_origins));
+    }
+
+    #[test]
+    fn allow_listed_origins() {
+        let allowed_origins = ["https://example.org".to_string()];
+
+        let headers_1 = headers("localhost:41416", Some("https://example.org"));
+        assert!(is_allowed_origin(&headers_1, &allowed_origins));
+
+        // Only the listed origins are allowed, even from the same host.
+        let headers_2 = headers("localhost:41416", Some("http://localhost:8080"));
+        assert!(!is_allowed_origin(&headers_2, &allowed_origins));
+
+        assert!(is_allowed_origin(&headers_2, &["*".to_string()]));
+    }
+}